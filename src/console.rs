@@ -1,4 +1,6 @@
 //! Console IO module
+//!
+//! Output goes straight to the UART driver, input is read through the TTY.
 
 use crate::platform::print_char;
 use core::fmt::{self, Write};
@@ -1,20 +1,209 @@
 //! Clock module for handling timer interrupt.
-use core::arch::asm;
+//!
+//! The CP0 Count register runs freely at a rate measured at boot against the RTC, and the
+//! tick, the time slice of an env, is derived from it for the tick frequency chosen at
+//! boot. Compare is set one tick ahead whenever an env starts running.
+//!
+//! The kernel also keeps one-shot timers, e.g. to wake envs from `sys_sleep`. They run
+//! on the first tick past their deadline, and while the kernel idles there is no tick at all: Compare is programmed
+//! for the earliest deadline only, if there is any.
+use crate::{
+    mutex::{FakeLock, Mutex},
+    platform::{
+        ioread_byte, iowrite_byte,
+        malta::{RTC_ADDR, RTC_DATA, RTC_REG_A, RTC_REG_A_INIT, RTC_UIP},
+    },
+};
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use lazy_static::lazy_static;
+use log::{info, warn};
 
-const TIMER_INTERVAL: u32 = 500_000;
+/// Tick frequency unless set at boot
+pub const DEFAULT_HZ: u32 = 200;
+/// Count rate assumed if it cannot be measured, that of the default QEMU Malta CPU
+const FALLBACK_COUNT_FREQ: u32 = 100_000_000;
+/// Least distance of Compare from Count for an interrupt not to be missed
+const MIN_DELTA: u32 = 100;
 
-/// Reset the CP0 Count and Compare registers for timer interrupt.
-/// 
+/// Count cycles per second
+static COUNT_FREQ: AtomicU32 = AtomicU32::new(FALLBACK_COUNT_FREQ);
+/// Count cycles per tick
+static TICK_CYCLES: AtomicU32 = AtomicU32::new(FALLBACK_COUNT_FREQ / DEFAULT_HZ);
+
+/// One-shot timer
+struct Timer {
+    /// Count value it expires at
+    deadline: u32,
+    handler: fn(usize),
+    /// Argument of handler
+    arg: usize,
+}
+
+lazy_static! {
+    static ref TIMERS: FakeLock<Vec<Timer>> = FakeLock::new(Vec::new());
+}
+
+/// Read the CP0 Count register
+fn read_count() -> u32 {
+    let count: u32;
+    unsafe { asm!("mfc0 {}, $9", out(reg) count) };
+    count
+}
+
+/// Write the CP0 Compare register, which also acknowledges the timer interrupt
+fn write_compare(compare: u32) {
+    unsafe { asm!("mtc0 {}, $11", in(reg) compare) };
+}
+
+/// Check if Count value a comes before b, in the half of the Count range ahead of it
+const fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Read RTC register A
+fn rtc_reg_a() -> u8 {
+    unsafe {
+        iowrite_byte(RTC_ADDR, RTC_REG_A);
+        ioread_byte(RTC_DATA)
+    }
+}
+
+/// Wait for the start of the next RTC update, once a second
+///
+/// # Returns
+///
+/// false if the RTC does not seem to be ticking
+fn rtc_wait_update() -> bool {
+    let start = read_count();
+    // far beyond two seconds at any plausible Count rate
+    let timed_out = || read_count().wrapping_sub(start) > i32::MAX as u32;
+    while rtc_reg_a() & RTC_UIP != 0 {
+        if timed_out() {
+            return false;
+        }
+    }
+    while rtc_reg_a() & RTC_UIP == 0 {
+        if timed_out() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Measure the Count rate over one second of the RTC
+///
+/// Takes up to two seconds.
+fn measure_count_freq() -> Option<u32> {
+    unsafe {
+        iowrite_byte(RTC_ADDR, RTC_REG_A);
+        iowrite_byte(RTC_DATA, RTC_REG_A_INIT);
+    }
+    if !rtc_wait_update() {
+        return None;
+    }
+    let start = read_count();
+    if !rtc_wait_update() {
+        return None;
+    }
+    Some(read_count().wrapping_sub(start))
+}
+
+/// Measure the Count rate and set the tick frequency to hz
+pub fn init(hz: u32) {
+    let freq = measure_count_freq().unwrap_or_else(|| {
+        warn!(
+            "clock: RTC is not ticking, assuming Count runs at {} Hz",
+            FALLBACK_COUNT_FREQ
+        );
+        FALLBACK_COUNT_FREQ
+    });
+    let hz = if (1..=freq / MIN_DELTA).contains(&hz) {
+        hz
+    } else {
+        warn!(
+            "clock: invalid tick frequency {} Hz, using {} Hz",
+            hz, DEFAULT_HZ
+        );
+        DEFAULT_HZ
+    };
+    COUNT_FREQ.store(freq, Ordering::Relaxed);
+    TICK_CYCLES.store(freq / hz, Ordering::Relaxed);
+    info!(
+        "clock: Count runs at {}.{:03} MHz, {} Hz tick",
+        freq / 1_000_000,
+        freq / 1000 % 1000,
+        hz
+    );
+}
+
+/// Reset the CP0 Compare register for the next timer interrupt, one tick from now.
+///
 /// # Safety
-/// 
+///
 /// This function is unsafe because it uses inline assembly.
 #[inline(always)]
 pub unsafe fn reset_kclock() {
-    asm!(
-        ".set noat",
-        "mtc0 {}, $11",
-        "mtc0 $zero, $9",
-        ".set at",
-        in(reg) TIMER_INTERVAL,
-    );
+    write_compare(read_count().wrapping_add(TICK_CYCLES.load(Ordering::Relaxed)));
+}
+
+/// Run handler with arg in interrupt context once us microseconds have passed
+///
+/// Delays are cut to half the Count range, over 20 seconds at 100 MHz.
+pub fn add_timer(us: u32, handler: fn(usize), arg: usize) {
+    let cycles = us as u64 * COUNT_FREQ.load(Ordering::Relaxed) as u64 / 1_000_000;
+    let deadline = read_count().wrapping_add(cycles.min(i32::MAX as u64) as u32);
+    TIMERS.lock().push(Timer {
+        deadline,
+        handler,
+        arg,
+    });
+}
+
+/// Check if any timer is pending
+pub fn has_timers() -> bool {
+    !TIMERS.lock().is_empty()
+}
+
+/// Run the handlers of expired timers, called on timer interrupts
+pub fn run_timers() {
+    let now = read_count();
+    let expired: Vec<Timer> = {
+        let mut timers = TIMERS.lock();
+        let (expired, pending) = core::mem::take(&mut *timers)
+            .into_iter()
+            .partition(|timer| !before(now, timer.deadline));
+        *timers = pending;
+        expired
+    };
+    // handlers may add timers
+    expired.iter().for_each(|timer| (timer.handler)(timer.arg));
+}
+
+/// Program Compare for the earliest timer deadline before the kernel idles
+///
+/// # Returns
+///
+/// false if no timer is pending, the timer interrupt must then stay masked
+pub fn program_idle_timer() -> bool {
+    let now = read_count();
+    let Some(deadline) = TIMERS
+        .lock()
+        .iter()
+        .map(|timer| timer.deadline)
+        .min_by_key(|&deadline| deadline.wrapping_sub(now) as i32)
+    else {
+        return false;
+    };
+    // an expired deadline must still raise an interrupt
+    let earliest = now.wrapping_add(MIN_DELTA);
+    write_compare(if before(deadline, earliest) {
+        earliest
+    } else {
+        deadline
+    });
+    true
 }
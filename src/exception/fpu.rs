@@ -0,0 +1,130 @@
+//! Lazy FPU context switching.
+//!
+//! Envs run with CU1 disabled until they use the FPU, which raises a Coprocessor Unusable
+//! exception. The FPU then becomes theirs: the registers of its previous owner are saved
+//! to that env's context, those of the new owner are restored, and CU1 is enabled for it.
+//! An env switch leaves the FPU registers alone and only disables CU1 for envs not owning
+//! it, so an env which is the only one using the FPU never has its context switched.
+
+use super::trapframe::Trapframe;
+use crate::{
+    const_export_usize,
+    mutex::Mutex,
+    platform::cp0reg::{STATUS_CU1, STATUS_UM},
+    pm::{debug, env_destroy, schedule, Env, ENV_MANAGER},
+};
+use alloc::boxed::Box;
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::warn;
+
+global_asm!(include_str!("../../asm/exception/fpu.S"));
+
+/// FPU registers of an env
+#[repr(C)]
+#[derive(Clone, Default, Debug)]
+pub struct FpuContext {
+    /// $f0 to $f31
+    pub regs: [u32; 32],
+    /// Control and status register
+    pub fcsr: u32,
+}
+
+const_export_usize!(FPU_FCSR, 0x80);
+
+extern "C" {
+    fn fpu_save(ctx: *mut FpuContext);
+    fn fpu_restore(ctx: *const FpuContext);
+}
+
+/// Id of the env whose registers are in the FPU, 0 for none
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// Check if the CPU has an FPU, Config1.FP
+fn fpu_present() -> bool {
+    let config1: u32;
+    unsafe { asm!("mfc0 {}, $16, 1", out(reg) config1) };
+    config1 & 1 != 0
+}
+
+/// Enable CU1 for the kernel itself, to move FPU registers
+fn enable_cu1() {
+    unsafe {
+        asm!(
+            "mfc0 {status}, $12",
+            "or {status}, {status}, {cu1}",
+            "mtc0 {status}, $12",
+            status = out(reg) _,
+            cu1 = in(reg) STATUS_CU1,
+        );
+    }
+}
+
+/// Check if the registers of the env of id are in the FPU
+pub fn owns(id: usize) -> bool {
+    OWNER.load(Ordering::Relaxed) == id
+}
+
+/// Save the FPU registers of their owner, if it is env, to its context
+pub fn save(env: &Env) {
+    if !owns(env.id) {
+        return;
+    }
+    enable_cu1();
+    let ctx = env.ext().fpu.get_or_insert_with(Box::default);
+    unsafe { fpu_save(&mut **ctx) };
+}
+
+/// Forget the FPU registers of env, which is being freed
+pub fn release(env: &Env) {
+    let _ = OWNER.compare_exchange(env.id, 0, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Hand the FPU over to env
+fn switch_to(env: &Env) {
+    let owner = OWNER.load(Ordering::Relaxed);
+    if owner == env.id {
+        return;
+    }
+    enable_cu1();
+    if owner != 0 {
+        // the owner is released when freed, it must still exist
+        if let Ok(owner) = ENV_MANAGER.lock().env_from_id(owner, false) {
+            let ctx = owner.ext().fpu.get_or_insert_with(Box::default);
+            unsafe { fpu_save(&mut **ctx) };
+        }
+    }
+    // an env using the FPU for the first time starts from cleared registers
+    let ctx = env.ext().fpu.get_or_insert_with(Box::default);
+    unsafe { fpu_restore(&**ctx) };
+    OWNER.store(env.id, Ordering::Relaxed);
+}
+
+/// Coprocessor Unusable exception handler
+///
+/// Gives the FPU to the current env on its first use of it since it last owned it. Use
+/// of any other coprocessor, or of the FPU on a CPU without one, kills the env or stops
+/// it if traced.
+#[no_mangle]
+pub unsafe extern "C" fn do_cpu(tf: *mut Trapframe) {
+    let tf = &mut *tf;
+    let unit = (tf.cp0_cause >> 28) & 0x3;
+    if tf.cp0_status as usize & STATUS_UM == 0 {
+        panic!("Coprocessor {} unusable in kernel\n {}", unit, tf);
+    }
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    if unit == 1 && fpu_present() {
+        switch_to(env);
+        tf.cp0_status |= STATUS_CU1 as u32;
+        return;
+    }
+    debug::stop_on_exception(env, tf);
+    warn!(
+        "{:08x}: coprocessor {} unusable at 0x{:08x}, killing...",
+        env.id, unit, tf.cp0_epc
+    );
+    env_destroy(env);
+    schedule(true);
+}
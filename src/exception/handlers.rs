@@ -1,19 +1,32 @@
 //! Some of the exception handlers
 use log::warn;
 
-use crate::pm::{env_destroy, schedule, ENV_MANAGER};
+use crate::mm::layout::{USTACKTOP, UXSTACKTOP};
 use crate::mutex::Mutex;
+use crate::platform::cp0reg::STATUS_UM;
+use crate::pm::{debug, env_destroy, schedule, Env, ENV_MANAGER};
 
-use super::trapframe::Trapframe;
+use super::trapframe::{Trapframe, TF_SIZE};
+use super::unaligned;
+use core::mem::size_of;
 
 /// Execute when address error occurs
+///
+/// Unaligned accesses of user mode are emulated for envs which asked for it, the env is
+/// killed on any other address error, or stopped if traced.
 #[no_mangle]
 pub unsafe extern "C" fn do_address_error(tf: *mut Trapframe) {
-    // Kill the process
-    let tf = &*tf;
+    let tf = &mut *tf;
     // AdEL for false, AdES for true
     let extype = ((tf.cp0_cause >> 2) & 0x3f) == 5;
     if let Some(env) = ENV_MANAGER.lock().curenv() {
+        let from_user = tf.cp0_status as usize & STATUS_UM != 0;
+        if from_user && env.ext().emulate_unaligned && unaligned::emulate(tf) {
+            return;
+        }
+        if from_user {
+            debug::stop_on_exception(env, tf);
+        }
         let msg = if extype { "AdES" } else { "AdEL" };
         warn!(
             "{:08x}: {} at 0x{:08x} for 0x{:08x}, killing...",
@@ -26,10 +39,93 @@ pub unsafe extern "C" fn do_address_error(tf: *mut Trapframe) {
     }
 }
 
+/// Name of the exceptions forwarded to user handlers, by exception code
+fn forwarded_name(code: u32) -> Option<&'static str> {
+    match code {
+        9 => Some("breakpoint"),
+        10 => Some("reserved instruction"),
+        12 => Some("overflow"),
+        13 => Some("trap"),
+        _ => None,
+    }
+}
+
+/// Run the user handler at entry for the exception in tf
+///
+/// The trapframe is copied to the user exception stack, unless the env already runs on
+/// it, and the handler is called with a pointer to the copy. It resumes the env with
+/// `sys_set_trapframe`.
+///
+/// # Safety
+///
+/// tf must be the trapframe of the current env, taken in user mode.
+pub unsafe fn enter_user_handler(tf: *mut Trapframe, entry: usize) {
+    let tmp_tf = *tf;
+
+    if !(USTACKTOP..UXSTACKTOP).contains(&((*tf).regs[29] as usize)) {
+        (*tf).regs[29] = UXSTACKTOP as u32;
+    }
+    (*tf).regs[29] -= TF_SIZE as u32;
+    *((*tf).regs[29] as *mut Trapframe) = tmp_tf;
+    (*tf).regs[4] = (*tf).regs[29];
+    (*tf).regs[29] -= size_of::<u32>() as u32;
+    (*tf).cp0_epc = entry as u32;
+}
+
+/// Execute when a breakpoint, reserved instruction, overflow or trap exception occurs
+///
+/// Exceptions of user mode stop a traced env, and otherwise go to the handler the env
+/// registered, the env is killed if there is none. EPC still points to the faulting
+/// instruction, or to the branch before it if the Cause BD bit is set, so the handler
+/// must move it on before resuming.
+///
+/// Breaks for the GDB stub, when built in, are served by it first.
+#[no_mangle]
+pub unsafe extern "C" fn do_forward(tf: *mut Trapframe) {
+    #[cfg(feature = "gdb_stub")]
+    if (*tf).cp0_cause >> 2 & 0x1f == 9 && crate::gdb::handle_trap(&mut *tf) {
+        return;
+    }
+    if (*tf).cp0_status as usize & STATUS_UM == 0 {
+        do_unhandled(tf);
+    }
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    debug::stop_on_exception(env, &*tf);
+    match env.ext().user_exception_entry {
+        0 => kill_on_exception(env, &*tf),
+        entry => enter_user_handler(tf, entry),
+    }
+}
+
+/// Kill env for an exception it has no handler for
+fn kill_on_exception(env: &mut Env, tf: &Trapframe) -> ! {
+    let code = (tf.cp0_cause >> 2) & 0x1f;
+    match forwarded_name(code) {
+        Some(name) => warn!(
+            "{:08x}: {} at 0x{:08x}, killing...",
+            env.id, name, tf.cp0_epc
+        ),
+        None => warn!(
+            "{:08x}: exception {} at 0x{:08x}, killing...",
+            env.id, code, tf.cp0_epc
+        ),
+    }
+    env_destroy(env);
+    schedule(true)
+}
+
 /// Execute when undefined error occurs
+///
+/// Exceptions of user mode stop a traced env and kill others, those of the kernel panic.
 #[no_mangle]
-pub extern "C" fn do_unhandled(tf: *mut Trapframe) {
+pub extern "C" fn do_unhandled(tf: *mut Trapframe) -> ! {
     let tf = unsafe { &*tf };
+    if tf.cp0_status as usize & STATUS_UM != 0 {
+        if let Some(env) = ENV_MANAGER.lock().curenv() {
+            debug::stop_on_exception(env, tf);
+            kill_on_exception(env, tf);
+        }
+    }
     panic!(
         "Unhandled exception,\n Exception type: {},\n {}",
         ((tf.cp0_cause >> 2) & 0x3f),
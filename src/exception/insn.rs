@@ -0,0 +1,142 @@
+//! MIPS32 instruction decoding shared by the exception handlers.
+
+use super::trapframe::Trapframe;
+
+const OP_SPECIAL: u32 = 0x00;
+const OP_REGIMM: u32 = 0x01;
+const OP_J: u32 = 0x02;
+const OP_JAL: u32 = 0x03;
+const OP_BEQ: u32 = 0x04;
+const OP_BNE: u32 = 0x05;
+const OP_BLEZ: u32 = 0x06;
+const OP_BGTZ: u32 = 0x07;
+const OP_COP1: u32 = 0x11;
+const OP_BEQL: u32 = 0x14;
+const OP_BNEL: u32 = 0x15;
+const OP_BLEZL: u32 = 0x16;
+const OP_BGTZL: u32 = 0x17;
+
+const FUNCT_JR: u32 = 0x08;
+const FUNCT_JALR: u32 = 0x09;
+
+/// COP1 rs field of bc1f, bc1t and their likely variants
+const COP1_BC: usize = 0x08;
+
+/// Instruction word of `break`
+pub const BREAK: u32 = 0x0000_000d;
+
+/// Fields of an instruction word
+#[derive(Clone, Copy)]
+pub struct Insn(pub u32);
+
+impl Insn {
+    /// Major opcode
+    pub const fn opcode(self) -> u32 {
+        self.0 >> 26
+    }
+    /// rs register
+    pub const fn rs(self) -> usize {
+        (self.0 >> 21 & 0x1f) as usize
+    }
+    /// rt register
+    pub const fn rt(self) -> usize {
+        (self.0 >> 16 & 0x1f) as usize
+    }
+    /// rd register
+    pub const fn rd(self) -> usize {
+        (self.0 >> 11 & 0x1f) as usize
+    }
+    /// Function field of SPECIAL instructions
+    pub const fn funct(self) -> u32 {
+        self.0 & 0x3f
+    }
+    /// Sign extended immediate
+    pub const fn simm(self) -> u32 {
+        self.0 as i16 as i32 as u32
+    }
+    /// Jump target field
+    pub const fn index(self) -> u32 {
+        self.0 & 0x03ff_ffff
+    }
+
+    /// Check if this is a bc1f or bc1t branch, which depends on the FPU condition
+    pub const fn is_cop1_branch(self) -> bool {
+        self.opcode() == OP_COP1 && self.rs() == COP1_BC
+    }
+
+    /// Target of the relative branch at pc
+    pub const fn branch_target(self, pc: u32) -> u32 {
+        pc.wrapping_add(4).wrapping_add(self.simm() << 2)
+    }
+}
+
+/// Outcome of a branch or jump
+pub struct Branch {
+    /// Address execution goes on at after the delay slot
+    pub next: u32,
+    /// Register the return address, the branch address plus 8, is written to
+    pub link: Option<usize>,
+}
+
+/// Evaluate branch at pc against the registers of tf
+///
+/// # Returns
+///
+/// None if the instruction is no branch known here, the bc1f and bc1t ones included
+pub fn decode_branch(tf: &Trapframe, pc: u32, branch: Insn) -> Option<Branch> {
+    let rs = tf.regs[branch.rs()];
+    let rt = tf.regs[branch.rt()];
+    let link = pc.wrapping_add(8);
+    let (taken, link_reg) = match branch.opcode() {
+        OP_SPECIAL => {
+            let link = match branch.funct() {
+                FUNCT_JR => None,
+                FUNCT_JALR => Some(branch.rd()),
+                _ => return None,
+            };
+            return Some(Branch { next: rs, link });
+        }
+        OP_J | OP_JAL => {
+            return Some(Branch {
+                next: (pc.wrapping_add(4) & 0xf000_0000) | branch.index() << 2,
+                link: (branch.opcode() == OP_JAL).then_some(31),
+            });
+        }
+        OP_REGIMM => {
+            // bltz, bgez, their likely variants and the ones linking in $31
+            let cond = branch.rt();
+            if !matches!(cond, 0x00..=0x03 | 0x10..=0x13) {
+                return None;
+            }
+            let negative = (rs as i32) < 0;
+            let taken = if cond & 1 == 0 { negative } else { !negative };
+            (taken, (cond & 0x10 != 0).then_some(31))
+        }
+        OP_BEQ | OP_BEQL => (rs == rt, None),
+        OP_BNE | OP_BNEL => (rs != rt, None),
+        OP_BLEZ | OP_BLEZL => (rs as i32 <= 0, None),
+        OP_BGTZ | OP_BGTZL => (rs as i32 > 0, None),
+        _ => return None,
+    };
+    Some(Branch {
+        next: if taken {
+            branch.branch_target(pc)
+        } else {
+            link
+        },
+        link: link_reg,
+    })
+}
+
+/// Addresses of the instructions that may run after the one at pc, for single-stepping
+///
+/// Both ways of a bc1f or bc1t are given, the FPU condition is not known here.
+pub fn step_targets(tf: &Trapframe, pc: u32, insn: Insn) -> [Option<u32>; 2] {
+    if insn.is_cop1_branch() {
+        [Some(insn.branch_target(pc)), Some(pc.wrapping_add(8))]
+    } else if let Some(branch) = decode_branch(tf, pc, insn) {
+        [Some(branch.next), None]
+    } else {
+        [Some(pc.wrapping_add(4)), None]
+    }
+}
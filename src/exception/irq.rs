@@ -0,0 +1,222 @@
+//! Interrupt handling.
+//!
+//! Two hardware interrupts are in use: the CP0 timer (`STATUS_IM7`), which drives the
+//! scheduler, and the i8259 pair (`STATUS_IM2`), which multiplexes the 16 IRQ lines of
+//! the board devices. Drivers register a handler per IRQ line with [`register_irq`],
+//! its line is unmasked from then on, and may mask it for a while with [`mask_irq`] or
+//! release it with [`unregister_irq`]. Interrupts are acknowledged and ended before the
+//! handler runs, so a handler may reschedule and never return, and every line keeps a
+//! count of the interrupts it raised, which `sys_irq_stats` reports.
+//!
+//! # Note
+//!
+//! Interrupts are only taken in user mode, the kernel runs with them disabled, except
+//! while it idles in [`wait_for_interrupt`]. An interrupt taken there right before the
+//! `wait` instruction skips it, so that the envs it woke are not left waiting.
+
+use super::{
+    clock::{self, reset_kclock},
+    trapframe::Trapframe,
+};
+use crate::{
+    error::MosError,
+    mutex::{FakeLock, Mutex},
+    platform::{
+        cp0reg::{STATUS_IE, STATUS_IM2, STATUS_IM7, STATUS_UM},
+        i8259::{self, NIRQ},
+        malta::PIC_CASCADE_IRQ,
+    },
+    pm::schedule,
+};
+use core::{arch::asm, ptr::addr_of};
+use lazy_static::lazy_static;
+use log::warn;
+
+/// Handler of an IRQ line, called with the line number
+pub type IrqHandler = fn(usize);
+
+/// Interrupt counts
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IrqStats {
+    /// Interrupts handled per IRQ line
+    pub count: [u32; NIRQ],
+    /// Spurious interrupts of either controller
+    pub spurious: u32,
+    /// Interrupts of lines without a handler
+    pub unhandled: u32,
+}
+
+/// Registered handlers and counts
+struct IrqManager {
+    handlers: [Option<IrqHandler>; NIRQ],
+    stats: IrqStats,
+}
+
+lazy_static! {
+    static ref IRQ_MANAGER: FakeLock<IrqManager> = FakeLock::new(IrqManager {
+        handlers: [None; NIRQ],
+        stats: IrqStats {
+            count: [0; NIRQ],
+            spurious: 0,
+            unhandled: 0,
+        },
+    });
+}
+
+/// Initialize the interrupt controllers, every IRQ line masked
+pub fn init() {
+    i8259::init();
+    self_test();
+}
+
+/// Check registering, masking and releasing a handler on a line nobody uses
+///
+/// Interrupts are still disabled, the line raises none meanwhile.
+fn self_test() {
+    fn handler(_irq: usize) {}
+    let irq = (0..NIRQ)
+        .find(|&irq| irq != PIC_CASCADE_IRQ && IRQ_MANAGER.lock().handlers[irq].is_none())
+        .expect("irq: no free line for the self test");
+    assert!(
+        register_irq(irq, handler).is_ok(),
+        "irq: free line not registered"
+    );
+    assert!(!i8259::is_masked(irq), "irq: registered line still masked");
+    assert!(
+        register_irq(irq, handler).is_err(),
+        "irq: line registered twice"
+    );
+    mask_irq(irq);
+    assert!(i8259::is_masked(irq), "irq: line not masked");
+    unmask_irq(irq);
+    assert!(!i8259::is_masked(irq), "irq: line not unmasked");
+    unregister_irq(irq);
+    assert!(i8259::is_masked(irq), "irq: released line not masked");
+    assert!(
+        register_irq(irq, handler).is_ok(),
+        "irq: released line not free"
+    );
+    unregister_irq(irq);
+}
+
+/// Register handler for IRQ line irq and unmask the line
+///
+/// # Returns
+///
+/// `MosError::Inval` if irq is not a valid line or already has a handler
+pub fn register_irq(irq: usize, handler: IrqHandler) -> Result<(), MosError> {
+    let mut manager = IRQ_MANAGER.lock();
+    match manager.handlers.get_mut(irq) {
+        Some(slot @ None) => *slot = Some(handler),
+        _ => return Err(MosError::Inval),
+    }
+    i8259::unmask(irq);
+    Ok(())
+}
+
+/// Mask IRQ line irq and remove its handler
+pub fn unregister_irq(irq: usize) {
+    if irq < NIRQ {
+        i8259::mask(irq);
+        IRQ_MANAGER.lock().handlers[irq] = None;
+    }
+}
+
+/// Stop IRQ line irq from raising interrupts, its handler stays registered
+pub fn mask_irq(irq: usize) {
+    if irq < NIRQ {
+        i8259::mask(irq);
+    }
+}
+
+/// Let IRQ line irq raise interrupts again
+pub fn unmask_irq(irq: usize) {
+    if irq < NIRQ {
+        i8259::unmask(irq);
+    }
+}
+
+/// Acquire interrupt counts
+pub fn irq_stats() -> IrqStats {
+    IRQ_MANAGER.lock().stats
+}
+
+/// Acknowledge and end the pending i8259 interrupt, then run its handler
+fn dispatch_pic() {
+    let irq = i8259::read_irq();
+    if i8259::is_spurious(irq) {
+        IRQ_MANAGER.lock().stats.spurious += 1;
+        return;
+    }
+    // the handler may not return
+    i8259::eoi(irq);
+    let handler = IRQ_MANAGER.lock().handlers[irq];
+    match handler {
+        Some(handler) => {
+            IRQ_MANAGER.lock().stats.count[irq] += 1;
+            handler(irq);
+        }
+        None => {
+            // nobody listens, keep the line quiet from now on
+            warn!("Unhandled IRQ {}, masking it.", irq);
+            IRQ_MANAGER.lock().stats.unhandled += 1;
+            i8259::mask(irq);
+        }
+    }
+}
+
+extern "C" {
+    /// The `wait` instruction of `wait_for_interrupt`
+    static idle_wait: u8;
+}
+
+/// Sleep until an interrupt has been served
+///
+/// Used by the scheduler when no env is runnable. There is no tick meanwhile, the timer
+/// interrupt is only enabled for the earliest timer deadline.
+#[inline(never)]
+pub fn wait_for_interrupt() {
+    let mut bits = STATUS_IM2 | STATUS_IE;
+    if clock::program_idle_timer() {
+        bits |= STATUS_IM7;
+    }
+    unsafe {
+        asm!(
+            "mfc0 {status}, $12",
+            "or {enabled}, {status}, {bits}",
+            "mtc0 {enabled}, $12",
+            ".globl idle_wait",
+            "idle_wait:",
+            "wait",
+            "mtc0 {status}, $12",
+            status = out(reg) _,
+            enabled = out(reg) _,
+            bits = in(reg) bits,
+        );
+    }
+}
+
+/// Interrupt handler
+///
+/// Device interrupts are served first. A timer interrupt then runs expired timers and
+/// ends the time slice of the current env, or is only acknowledged while the kernel idles.
+#[no_mangle]
+pub unsafe extern "C" fn do_irq(tf: *mut Trapframe) {
+    // the handlers may make an env runnable, the idle loop has to look again
+    if (*tf).cp0_epc as usize == addr_of!(idle_wait) as usize {
+        (*tf).cp0_epc += 4;
+    }
+    let pending = ((*tf).cp0_cause & (*tf).cp0_status) as usize;
+    if pending & STATUS_IM2 != 0 {
+        dispatch_pic();
+    }
+    if pending & STATUS_IM7 != 0 {
+        clock::run_timers();
+        if (*tf).cp0_status as usize & STATUS_UM == 0 {
+            reset_kclock();
+        } else {
+            schedule(false);
+        }
+    }
+}
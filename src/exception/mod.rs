@@ -3,9 +3,14 @@
 //! This module provides exception handling functionality for the kernel. It defines the exception
 //! handler vector and initializes the exception handling feature.
 
-mod clock;
+pub mod clock;
+pub mod fpu;
 mod handlers;
+pub mod insn;
+pub mod irq;
 mod trapframe;
+pub mod unaligned;
+pub mod watch;
 
 use core::{
     arch::{asm, global_asm},
@@ -15,6 +20,7 @@ use core::{
 use log::info;
 
 pub use clock::reset_kclock;
+pub use handlers::enter_user_handler;
 pub use trapframe::{Trapframe, TF_SIZE};
 
 global_asm!(include_str!("../../asm/exception/exception_entry.S"));
@@ -30,6 +36,9 @@ extern "C" {
     fn _handle_mod();
     fn _handle_syscall();
     fn _handle_unhandled();
+    fn _handle_cpu();
+    fn _handle_forward();
+    fn _handle_watch();
     fn _handle_ade();
 }
 
@@ -45,11 +54,11 @@ pub static exception_handlers: [Vector; 32] = [
     Vector(_handle_unhandled), // 06
     Vector(_handle_unhandled), // 07
     Vector(_handle_syscall),   // 08: Syscall
-    Vector(_handle_unhandled), // 09
-    Vector(_handle_unhandled), // 10
-    Vector(_handle_unhandled), // 11
-    Vector(_handle_unhandled), // 12
-    Vector(_handle_unhandled), // 13
+    Vector(_handle_forward),   // 09: Bp
+    Vector(_handle_forward),   // 10: RI
+    Vector(_handle_cpu),       // 11: CpU
+    Vector(_handle_forward),   // 12: Ov
+    Vector(_handle_forward),   // 13: Tr
     Vector(_handle_unhandled), // 14
     Vector(_handle_unhandled), // 15
     Vector(_handle_unhandled), // 16
@@ -59,7 +68,7 @@ pub static exception_handlers: [Vector; 32] = [
     Vector(_handle_unhandled), // 20
     Vector(_handle_unhandled), // 21
     Vector(_handle_unhandled), // 22
-    Vector(_handle_unhandled), // 23
+    Vector(_handle_watch),     // 23: WATCH
     Vector(_handle_unhandled), // 24
     Vector(_handle_unhandled), // 25
     Vector(_handle_unhandled), // 26
@@ -72,7 +81,8 @@ pub static exception_handlers: [Vector; 32] = [
 
 /// Init exception handling
 ///
-/// This function sets the exception entry point in the CP0 EBase register.
+/// This function sets the exception entry point in the CP0 EBase register
+/// and initializes the interrupt controllers.
 pub fn init() {
     extern "C" {
         static mut _tlb_refill_entry: u8;
@@ -89,4 +99,5 @@ pub fn init() {
             addr_of_mut!(_tlb_refill_entry) as u32
         );
     }
+    irq::init();
 }
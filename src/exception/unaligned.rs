@@ -0,0 +1,191 @@
+//! Emulation of unaligned loads and stores.
+//!
+//! Envs opt in with `sys_unaligned_op`. An address error of such an env is then decoded
+//! instead of killing it: if the instruction at EPC is a lw, lh, lhu, sw or sh, the
+//! access is done byte by byte and EPC moves past it. A faulting instruction in a branch
+//! delay slot is emulated as well, the branch before it is then completed by the kernel.
+
+use super::{
+    insn::{decode_branch, Insn},
+    trapframe::Trapframe,
+};
+use crate::mm::layout::is_illegal_user_va_range;
+use core::sync::atomic::{AtomicU32, Ordering};
+use log::trace;
+
+/// Unaligned accesses emulated since boot, reported by `sys_unaligned_op`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UnalignedStats {
+    /// Loads emulated
+    pub loads: u32,
+    /// Stores emulated
+    pub stores: u32,
+    /// Accesses emulated in a branch delay slot, also counted as loads or stores
+    pub delay_slots: u32,
+}
+
+static LOADS: AtomicU32 = AtomicU32::new(0);
+static STORES: AtomicU32 = AtomicU32::new(0);
+static DELAY_SLOTS: AtomicU32 = AtomicU32::new(0);
+
+/// Cause BD bit, the exception was taken in a branch delay slot
+const CAUSE_BD: u32 = 1 << 31;
+
+const OP_LH: u32 = 0x21;
+const OP_LW: u32 = 0x23;
+const OP_LHU: u32 = 0x25;
+const OP_SH: u32 = 0x29;
+const OP_SW: u32 = 0x2b;
+
+/// Unaligned access decoded from a load or store
+enum Access {
+    /// Load of size bytes to rt, sign extended if signed
+    Load {
+        rt: usize,
+        size: usize,
+        signed: bool,
+    },
+    /// Store of the size low bytes of rt
+    Store { rt: usize, size: usize },
+}
+
+impl Access {
+    fn decode(insn: Insn) -> Option<Self> {
+        let rt = insn.rt();
+        Some(match insn.opcode() {
+            OP_LW => Access::Load {
+                rt,
+                size: 4,
+                signed: false,
+            },
+            OP_LH => Access::Load {
+                rt,
+                size: 2,
+                signed: true,
+            },
+            OP_LHU => Access::Load {
+                rt,
+                size: 2,
+                signed: false,
+            },
+            OP_SW => Access::Store { rt, size: 4 },
+            OP_SH => Access::Store { rt, size: 2 },
+            _ => return None,
+        })
+    }
+}
+
+/// Read user instruction word at va
+fn fetch(va: u32) -> Option<Insn> {
+    if va & 3 != 0 || is_illegal_user_va_range(va as usize, 4) {
+        return None;
+    }
+    Some(Insn(unsafe { (va as *const u32).read() }))
+}
+
+/// Write register r of tf, $0 stays 0
+fn set_reg(tf: &mut Trapframe, r: usize, value: u32) {
+    if r != 0 {
+        tf.regs[r] = value;
+    }
+}
+
+/// Complete the branch at epc, whose delay slot has been emulated
+///
+/// # Returns
+///
+/// The address execution goes on at, None if the instruction is no branch known here
+fn complete_branch(tf: &mut Trapframe, epc: u32, branch: Insn) -> Option<u32> {
+    let branch = decode_branch(tf, epc, branch)?;
+    if let Some(link) = branch.link {
+        set_reg(tf, link, epc.wrapping_add(8));
+    }
+    Some(branch.next)
+}
+
+/// Emulate the unaligned access that raised the address error in tf
+///
+/// # Returns
+///
+/// false if it is no access that can be emulated, tf is then left alone
+pub fn emulate(tf: &mut Trapframe) -> bool {
+    let epc = tf.cp0_epc;
+    let in_delay_slot = tf.cp0_cause & CAUSE_BD != 0;
+    let insn_va = if in_delay_slot {
+        epc.wrapping_add(4)
+    } else {
+        epc
+    };
+    // an unaligned instruction fetch is no access to emulate
+    if tf.cp0_badvaddr == insn_va {
+        return false;
+    }
+    let Some(insn) = fetch(insn_va) else {
+        return false;
+    };
+    let Some(access) = Access::decode(insn) else {
+        return false;
+    };
+
+    // the branch before takes effect first, its link is seen by the access
+    let mut resumed = *tf;
+    let next = if in_delay_slot {
+        match fetch(epc).and_then(|branch| complete_branch(&mut resumed, epc, branch)) {
+            Some(next) => next,
+            None => return false,
+        }
+    } else {
+        epc.wrapping_add(4)
+    };
+
+    let va = resumed.regs[insn.rs()].wrapping_add(insn.simm());
+    let size = match access {
+        Access::Load { size, .. } | Access::Store { size, .. } => size,
+    };
+    if is_illegal_user_va_range(va as usize, size) {
+        return false;
+    }
+
+    let bytes = va as *mut u8;
+    match access {
+        Access::Load { rt, size, signed } => {
+            let mut value = 0u32;
+            for i in (0..size).rev() {
+                value = value << 8 | unsafe { bytes.add(i).read() } as u32;
+            }
+            if signed && size == 2 {
+                value = value as i16 as i32 as u32;
+            }
+            set_reg(&mut resumed, rt, value);
+            LOADS.fetch_add(1, Ordering::Relaxed);
+        }
+        Access::Store { rt, size } => {
+            let value = resumed.regs[rt];
+            for i in 0..size {
+                unsafe { bytes.add(i).write((value >> (8 * i)) as u8) };
+            }
+            STORES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if in_delay_slot {
+        DELAY_SLOTS.fetch_add(1, Ordering::Relaxed);
+    }
+    trace!(
+        "unaligned access at 0x{:08x} for 0x{:08x} emulated",
+        insn_va,
+        va
+    );
+    resumed.cp0_epc = next;
+    *tf = resumed;
+    true
+}
+
+/// Acquire counts of emulated accesses
+pub fn stats() -> UnalignedStats {
+    UnalignedStats {
+        loads: LOADS.load(Ordering::Relaxed),
+        stores: STORES.load(Ordering::Relaxed),
+        delay_slots: DELAY_SLOTS.load(Ordering::Relaxed),
+    }
+}
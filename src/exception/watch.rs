@@ -0,0 +1,110 @@
+//! Data watchpoints on user memory.
+//!
+//! An env may carry one watchpoint, set by itself or its parent with
+//! `sys_set_watchpoint`, which is loaded into the first CP0 WatchLo/WatchHi pair by
+//! `env_run` while the env runs. The pair matches the ASID of the env only, and the
+//! doubleword holding the watched address.
+//!
+//! A hit raises a Watch exception before the access is done. The EPC of the access is
+//! then sent by IPC to the env that set the watchpoint, from the watched env, and the
+//! watchpoint is removed so the env can go on. The owner sets it again to catch the next
+//! access. Hits are not reported while the owner is not receiving, nor for accesses made
+//! by the kernel in syscalls, after which the watchpoint is only loaded again the next
+//! time the env is switched to. A traced env is also stopped on the hit, see `debug`.
+
+use super::trapframe::Trapframe;
+use crate::{
+    mutex::Mutex,
+    platform::cp0reg::STATUS_UM,
+    pm::{debug, ipc_send_value, Env, ENV_MANAGER},
+};
+use core::arch::asm;
+use log::{info, warn};
+
+bitflags! {
+    /// Accesses caught by a watchpoint, as in WatchLo
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct WatchKind: u32 {
+        /// Stores
+        const W = 1 << 0;
+        /// Loads
+        const R = 1 << 1;
+    }
+}
+
+/// Watchpoint on an env
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    /// Watched virtual address
+    pub va: usize,
+    /// Accesses caught
+    pub kind: WatchKind,
+    /// Env reported to, by id
+    pub owner: usize,
+}
+
+/// WatchHi: writing ones clears the I, R and W bits of the last hit
+const WATCHHI_CLEAR: u32 = 0x7;
+/// WatchHi ASID field shift
+const WATCHHI_ASID_SHIFT: u32 = 16;
+
+/// Check if the CPU has watch registers, Config1.WR
+pub fn watch_present() -> bool {
+    let config1: u32;
+    unsafe { asm!("mfc0 {}, $16, 1", out(reg) config1) };
+    config1 & (1 << 3) != 0
+}
+
+/// Write the first WatchLo/WatchHi pair
+fn write_watch(lo: u32, hi: u32) {
+    unsafe {
+        asm!(
+            "mtc0 {lo}, $18",
+            "mtc0 {hi}, $19",
+            lo = in(reg) lo,
+            hi = in(reg) hi,
+        );
+    }
+}
+
+/// Load the watch registers for env, about to run
+pub fn install(env: &Env) {
+    if !watch_present() {
+        return;
+    }
+    match env.ext().watch {
+        Some(watch) => write_watch(
+            (watch.va as u32 & !0x7) | watch.kind.bits(),
+            (env.asid as u32) << WATCHHI_ASID_SHIFT | WATCHHI_CLEAR,
+        ),
+        None => write_watch(0, WATCHHI_CLEAR),
+    }
+}
+
+/// Watch exception handler
+#[no_mangle]
+pub unsafe extern "C" fn do_watch(tf: *mut Trapframe) {
+    let tf = &*tf;
+    // nothing is reported for the kernel, it goes on unwatched until the next env_run
+    write_watch(0, WATCHHI_CLEAR);
+    if tf.cp0_status as usize & STATUS_UM == 0 {
+        return;
+    }
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    let Some(watch) = env.ext().watch.take() else {
+        return;
+    };
+    info!(
+        "{:08x}: watchpoint at 0x{:08x} hit at 0x{:08x}",
+        env.id, watch.va, tf.cp0_epc
+    );
+    let owner = ENV_MANAGER.lock().env_from_id(watch.owner, false);
+    if let Err(err) = owner.and_then(|owner| ipc_send_value(owner, env.id, tf.cp0_epc)) {
+        warn!(
+            "{:08x}: watchpoint hit not reported to {:08x}: {:?}",
+            env.id, watch.owner, err
+        );
+    }
+    // the watchpoint is gone, a tracer resumes the env past the access
+    debug::stop_on_exception(env, tf);
+}
@@ -0,0 +1,578 @@
+//! GDB remote serial protocol stub on the second UART.
+//!
+//! Built with the `gdb_stub` feature. Run QEMU with a second serial line, for instance
+//! `-serial mon:stdio -serial tcp::1234,server,nowait`, and attach with
+//! `target remote :1234`. The stub takes over the machine on a `break` it handles:
+//!
+//! - at boot if the kernel is started with the `gdb=1` boot argument
+//! - on a kernel panic, before the machine halts
+//! - on a `break` compiled into the kernel, once the stub is initialized
+//! - on the software breakpoints GDB set, in the kernel or in the current env
+//!
+//! GDB then reads and writes the registers of the trap, and memory: addresses below
+//! `ULIM` are those of the current env, KSEG0 and KSEG1 reach low memory and KSEG2 the
+//! vmalloc area and the kmap window. The machine stays stopped, with interrupts off,
+//! until GDB steps or continues. Single-stepping plants temporary breakpoints on every
+//! instruction that may come next.
+//!
+//! Breakpoints in user space belong to the env they were set in, and only stop it. A page
+//! of the env shared copy-on-write with others is copied before a breakpoint is written
+//! to it, so the others never see it.
+
+use crate::{
+    exception::{
+        insn::{step_targets, Insn, BREAK},
+        Trapframe,
+    },
+    mm::{
+        cache::sync_icache,
+        get_lowmem_pagenum,
+        highmem::kmap,
+        kseg2,
+        layout::{KSEG0, KSEG1, KSEG2, PAGE_SIZE, ULIM},
+        VA,
+    },
+    mutex::{FakeLock, Mutex},
+    platform::{
+        cp0reg::STATUS_UM,
+        ioread_byte, iowrite_byte,
+        malta::{
+            SERIAL2_BASE, SERIAL_BASE, SERIAL_DATA, SERIAL_DATA_READY, SERIAL_FCR,
+            SERIAL_FCR_ENABLE, SERIAL_IER, SERIAL_LSR, SERIAL_THR_EMPTY,
+        },
+    },
+    pm::{Env, ENV_MANAGER},
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    arch::asm,
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use lazy_static::lazy_static;
+use log::info;
+
+/// Registers in the `g` packet: $0 to $31, sr, lo, hi, bad, cause and pc
+const NUM_REGS: usize = 38;
+/// Registers known to GDB for MIPS32, the FPU ones are reported unavailable
+const NUM_GDB_REGS: usize = 72;
+/// Largest packet accepted, as told to GDB
+const PACKET_SIZE: usize = 0x400;
+
+/// Stop reply for SIGTRAP
+const STOP_TRAP: &str = "S05";
+
+/// Register of the second UART matching that of the first one
+const fn uart2(reg: usize) -> usize {
+    SERIAL2_BASE + (reg - SERIAL_BASE)
+}
+
+/// Software breakpoint
+struct Breakpoint {
+    va: usize,
+    /// Instruction replaced by `break`
+    insn: u32,
+    /// Env whose address space it is in, by id, None for the kernel
+    env: Option<usize>,
+    /// Planted for a single step, removed on the next stop
+    temporary: bool,
+}
+
+/// The stub is initialized and handles breaks
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// GDB is being served, breaks and panics then go on as without the stub
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BREAKPOINTS: FakeLock<Vec<Breakpoint>> = FakeLock::new(Vec::new());
+}
+
+/// Set up the second UART, polled, and let the stub handle breaks
+pub fn init() {
+    unsafe {
+        iowrite_byte(uart2(SERIAL_FCR), SERIAL_FCR_ENABLE);
+        iowrite_byte(uart2(SERIAL_IER), 0);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop in the stub right here
+pub fn breakpoint() {
+    unsafe { asm!("break") };
+}
+
+/// Hand the panicking kernel over to GDB, unless the panic comes from the stub itself
+pub fn enter_on_panic() {
+    if ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if !ENABLED.load(Ordering::Relaxed) {
+        init();
+    }
+    info!("gdb: waiting for GDB on the second serial line");
+    breakpoint();
+}
+
+/// Handle a Bp exception
+///
+/// # Returns
+///
+/// false if the break is not for the stub, tf is then left alone
+pub fn handle_trap(tf: &mut Trapframe) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) || ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let pc = tf.cp0_epc as usize;
+    let space = if tf.cp0_status as usize & STATUS_UM != 0 {
+        match ENV_MANAGER.lock().curenv() {
+            Some(env) => Some(env.id),
+            None => return false,
+        }
+    } else {
+        None
+    };
+    let ours = BREAKPOINTS
+        .lock()
+        .iter()
+        .any(|bp| bp.va == pc && bp.env == space);
+    // the env's own breaks go to its exception handler
+    if space.is_some() && !ours {
+        return false;
+    }
+    ACTIVE.store(true, Ordering::Relaxed);
+    remove_breakpoints(|bp| bp.temporary);
+    serve(tf, space);
+    ACTIVE.store(false, Ordering::Relaxed);
+    true
+}
+
+/// Serve GDB until it resumes the machine
+fn serve(tf: &mut Trapframe, space: Option<usize>) {
+    let mut packet = Vec::new();
+    send_packet(STOP_TRAP);
+    loop {
+        recv_packet(&mut packet);
+        let Ok(packet) = core::str::from_utf8(&packet) else {
+            send_packet("");
+            continue;
+        };
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => Some(String::from(STOP_TRAP)),
+            "g" => Some(read_registers(tf)),
+            "G" => Some(ok_or_error(write_registers(tf, args))),
+            "p" => read_register(tf, args),
+            "P" => Some(ok_or_error(write_register(tf, args))),
+            "m" => read_memory(space, args),
+            "M" => Some(ok_or_error(write_memory(space, args))),
+            "Z" | "z" => breakpoint_command(space, command == "Z", args),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(pc) => tf.cp0_epc = pc as u32,
+                        None => {
+                            send_packet("E01");
+                            continue;
+                        }
+                    }
+                }
+                skip_compiled_break(tf, space);
+                if command == "s" && !step(tf, space) {
+                    send_packet("E0e");
+                    continue;
+                }
+                return;
+            }
+            "D" | "k" => {
+                remove_breakpoints(|_| true);
+                skip_compiled_break(tf, space);
+                if command == "D" {
+                    send_packet("OK");
+                }
+                return;
+            }
+            "H" => Some(String::from("OK")),
+            "q" => Some(String::from(query(args))),
+            _ => Some(String::new()),
+        };
+        send_packet(reply.as_deref().unwrap_or("E0e"));
+    }
+}
+
+/// Reply to a general query, empty for those not supported
+fn query(args: &str) -> &'static str {
+    if args.starts_with("Supported") {
+        // PacketSize is given in hex
+        "PacketSize=400"
+    } else if args.starts_with("Attached") {
+        "1"
+    } else {
+        ""
+    }
+}
+
+fn ok_or_error(ok: bool) -> String {
+    String::from(if ok { "OK" } else { "E01" })
+}
+
+/* Transport */
+
+fn get_byte() -> u8 {
+    unsafe {
+        while ioread_byte(uart2(SERIAL_LSR)) & SERIAL_DATA_READY == 0 {}
+        ioread_byte(uart2(SERIAL_DATA))
+    }
+}
+
+fn put_byte(byte: u8) {
+    unsafe {
+        while ioread_byte(uart2(SERIAL_LSR)) & SERIAL_THR_EMPTY == 0 {}
+        iowrite_byte(uart2(SERIAL_DATA), byte);
+    }
+}
+
+/// Receive the data of a `$data#checksum` packet, acknowledging it
+fn recv_packet(buf: &mut Vec<u8>) {
+    loop {
+        while get_byte() != b'$' {}
+        buf.clear();
+        let mut sum = 0u8;
+        let mut byte = get_byte();
+        while byte != b'#' && byte != b'$' && buf.len() < PACKET_SIZE {
+            sum = sum.wrapping_add(byte);
+            buf.push(byte);
+            byte = get_byte();
+        }
+        if byte != b'#' {
+            put_byte(b'-');
+            continue;
+        }
+        let checksum = hex_digit(get_byte()).zip(hex_digit(get_byte()));
+        if checksum.map(|(hi, lo)| hi << 4 | lo) == Some(sum) {
+            put_byte(b'+');
+            return;
+        }
+        put_byte(b'-');
+    }
+}
+
+/// Send a packet until GDB acknowledges it
+fn send_packet(data: &str) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    loop {
+        put_byte(b'$');
+        data.bytes().for_each(put_byte);
+        put_byte(b'#');
+        put_byte(HEX[(sum >> 4) as usize]);
+        put_byte(HEX[(sum & 0xf) as usize]);
+        if get_byte() != b'-' {
+            return;
+        }
+    }
+}
+
+/* Encoding */
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Parse the bytes encoded in hex by s
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    let pairs = s.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+/// Parse a register value, in target byte order
+fn parse_register(s: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(s)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn push_register(out: &mut String, value: u32) {
+    for byte in value.to_le_bytes() {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+/* Registers */
+
+fn register(tf: &Trapframe, n: usize) -> Option<u32> {
+    Some(match n {
+        0..=31 => tf.regs[n],
+        32 => tf.cp0_status,
+        33 => tf.lo,
+        34 => tf.hi,
+        35 => tf.cp0_badvaddr,
+        36 => tf.cp0_cause,
+        37 => tf.cp0_epc,
+        _ => return None,
+    })
+}
+
+/// Write register n, $0 stays 0 and sr, bad and cause are read-only
+fn set_register(tf: &mut Trapframe, n: usize, value: u32) -> bool {
+    match n {
+        1..=31 => tf.regs[n] = value,
+        33 => tf.lo = value,
+        34 => tf.hi = value,
+        37 => tf.cp0_epc = value,
+        0 | 32 | 35 | 36 => {}
+        _ => return false,
+    }
+    true
+}
+
+fn read_registers(tf: &Trapframe) -> String {
+    let mut out = String::with_capacity(NUM_REGS * 8);
+    for n in 0..NUM_REGS {
+        push_register(&mut out, register(tf, n).unwrap());
+    }
+    out
+}
+
+fn write_registers(tf: &mut Trapframe, args: &str) -> bool {
+    if args.len() < NUM_REGS * 8 {
+        return false;
+    }
+    for n in 0..NUM_REGS {
+        match parse_register(&args[n * 8..n * 8 + 8]) {
+            Some(value) => {
+                set_register(tf, n, value);
+            }
+            None => return false,
+        }
+    }
+    true
+}
+
+fn read_register(tf: &Trapframe, args: &str) -> Option<String> {
+    let n = parse_hex(args)?;
+    let mut out = String::new();
+    match register(tf, n) {
+        Some(value) => push_register(&mut out, value),
+        None if n < NUM_GDB_REGS => out.push_str("xxxxxxxx"),
+        None => return None,
+    }
+    Some(out)
+}
+
+fn write_register(tf: &mut Trapframe, args: &str) -> bool {
+    let Some((n, value)) = args.split_once('=') else {
+        return false;
+    };
+    match (parse_hex(n), parse_register(value)) {
+        (Some(n), Some(value)) => set_register(tf, n, value) || n < NUM_GDB_REGS,
+        _ => false,
+    }
+}
+
+/* Memory */
+
+/// Acquire the env of id, the current one for a None space
+fn space_env(space: Option<usize>) -> Option<&'static mut Env> {
+    let manager = ENV_MANAGER.lock();
+    match space {
+        Some(id) => manager.env_from_id(id, false).ok(),
+        None => manager.curenv(),
+    }
+}
+
+/// Run f on the kernel address of the byte at va
+///
+/// User addresses are those of the env of space, or of the current env for a kernel
+/// space. They are made private to the env when written.
+///
+/// # Returns
+///
+/// None if va is not mapped
+fn with_byte<T>(
+    space: Option<usize>,
+    va: usize,
+    write: bool,
+    f: impl FnOnce(*mut u8) -> T,
+) -> Option<T> {
+    if (KSEG0..KSEG2).contains(&va) {
+        let pa = va & (KSEG1 - KSEG0 - 1);
+        return (pa < get_lowmem_pagenum() * PAGE_SIZE).then(|| f(va as *mut u8));
+    }
+    let page = if va < ULIM {
+        let env = space_env(space)?;
+        if write {
+            env.pgdir().make_private(env.asid, VA(va)).ok()?
+        } else {
+            env.pgdir().lookup(VA(va))?.1
+        }
+    } else {
+        kseg2::pgdir().lookup(VA(va))?.1
+    };
+    let kmap = kmap(page);
+    Some(f((kmap.va().0 + (va & (PAGE_SIZE - 1))) as *mut u8))
+}
+
+fn read_byte(space: Option<usize>, va: usize) -> Option<u8> {
+    with_byte(space, va, false, |byte| unsafe { byte.read_volatile() })
+}
+
+fn write_byte(space: Option<usize>, va: usize, value: u8) -> Option<()> {
+    with_byte(space, va, true, |byte| {
+        unsafe { byte.write_volatile(value) };
+        sync_icache(va, byte as usize, 1);
+    })
+}
+
+fn read_word(space: Option<usize>, va: usize) -> Option<u32> {
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_byte(space, va + i)?;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn write_word(space: Option<usize>, va: usize, value: u32) -> Option<()> {
+    for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+        write_byte(space, va + i, byte)?;
+    }
+    Some(())
+}
+
+/// Parse the `addr,length` of a memory command
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn read_memory(space: Option<usize>, args: &str) -> Option<String> {
+    let (addr, len) = parse_range(args)?;
+    let len = len.min(PACKET_SIZE / 2);
+    let mut out = String::with_capacity(len * 2);
+    for i in 0..len {
+        let _ = write!(out, "{:02x}", read_byte(space, addr.wrapping_add(i))?);
+    }
+    Some(out)
+}
+
+fn write_memory(space: Option<usize>, args: &str) -> bool {
+    let Some((range, data)) = args.split_once(':') else {
+        return false;
+    };
+    let (Some((addr, len)), Some(data)) = (parse_range(range), parse_hex_bytes(data)) else {
+        return false;
+    };
+    data.len() == len
+        && data
+            .into_iter()
+            .enumerate()
+            .all(|(i, byte)| write_byte(space, addr.wrapping_add(i), byte).is_some())
+}
+
+/* Breakpoints */
+
+/// Address space a breakpoint at va is planted in
+fn breakpoint_space(space: Option<usize>, va: usize) -> Option<Option<usize>> {
+    if va >= ULIM {
+        return Some(None);
+    }
+    // a stop in the kernel sets breakpoints in the current env
+    space
+        .or_else(|| ENV_MANAGER.lock().curenv().map(|env| env.id))
+        .map(Some)
+}
+
+/// Plant a breakpoint at va
+fn insert_breakpoint(space: Option<usize>, va: usize, temporary: bool) -> bool {
+    let Some(space) = breakpoint_space(space, va) else {
+        return false;
+    };
+    if va & 3 != 0 {
+        return false;
+    }
+    let mut breakpoints = BREAKPOINTS.lock();
+    if let Some(bp) = breakpoints
+        .iter_mut()
+        .find(|bp| bp.va == va && bp.env == space)
+    {
+        bp.temporary &= temporary;
+        return true;
+    }
+    let Some(insn) = read_word(space, va) else {
+        return false;
+    };
+    if write_word(space, va, BREAK).is_none() {
+        return false;
+    }
+    breakpoints.push(Breakpoint {
+        va,
+        insn,
+        env: space,
+        temporary,
+    });
+    true
+}
+
+/// Remove the breakpoints matching f, restoring their instructions
+///
+/// Those of envs that are gone are dropped with them.
+fn remove_breakpoints(f: impl Fn(&Breakpoint) -> bool) {
+    BREAKPOINTS.lock().retain(|bp| {
+        if !f(bp) {
+            return true;
+        }
+        if bp.env.is_none() || space_env(bp.env).is_some() {
+            let _ = write_word(bp.env, bp.va, bp.insn);
+        }
+        false
+    });
+}
+
+/// Serve `Z` and `z` packets, software breakpoints only
+fn breakpoint_command(space: Option<usize>, insert: bool, args: &str) -> Option<String> {
+    let mut fields = args.split(',');
+    if fields.next() != Some("0") {
+        return Some(String::new());
+    }
+    let va = parse_hex(fields.next()?)?;
+    Some(ok_or_error(if insert {
+        insert_breakpoint(space, va, false)
+    } else {
+        let Some(space) = breakpoint_space(space, va) else {
+            return Some(ok_or_error(false));
+        };
+        remove_breakpoints(|bp| bp.va == va && bp.env == space && !bp.temporary);
+        true
+    }))
+}
+
+/// Move past a `break` compiled in at the pc, which would otherwise stop again
+fn skip_compiled_break(tf: &mut Trapframe, space: Option<usize>) {
+    let pc = tf.cp0_epc as usize;
+    let planted = BREAKPOINTS
+        .lock()
+        .iter()
+        .any(|bp| bp.va == pc && bp.env == space);
+    if !planted && read_word(space, pc) == Some(BREAK) {
+        tf.cp0_epc += 4;
+    }
+}
+
+/// Plant temporary breakpoints on every instruction that may run after the one at the pc
+fn step(tf: &Trapframe, space: Option<usize>) -> bool {
+    let pc = tf.cp0_epc;
+    let Some(insn) = read_word(space, pc as usize).map(Insn) else {
+        return false;
+    };
+    step_targets(tf, pc, insn)
+        .into_iter()
+        .flatten()
+        .all(|target| insert_breakpoint(space, target as usize, true))
+}
@@ -58,6 +58,10 @@ pub mod include_bytes {
         pub bytes: Bytes,
     }
 
+    /// Page alignment, for images whose pages may be mapped into user space directly
+    #[repr(C, align(4096))]
+    pub struct PageAlign;
+
     /// Include a file as a byte slice aligned as a specific type.
     #[macro_export]
     macro_rules! include_bytes_align_as {
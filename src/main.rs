@@ -16,6 +16,8 @@ extern crate bitflags;
 mod console;
 mod error;
 mod exception;
+#[cfg(feature = "gdb_stub")]
+mod gdb;
 mod logging;
 mod macros;
 mod mm;
@@ -24,13 +26,16 @@ mod panic;
 mod platform;
 mod pm;
 mod syscall;
+mod tty;
 
 use crate::mutex::Mutex;
 use core::{
     arch::global_asm,
+    ffi::{c_char, CStr},
     include_str,
     ptr::{addr_of_mut, write_bytes},
 };
+use exception::clock::{self, DEFAULT_HZ};
 use log::info;
 use pm::schedule;
 
@@ -43,8 +48,8 @@ global_asm!(include_str!("../asm/init/entry.S"));
 ///
 #[no_mangle]
 pub extern "C" fn kernel_init(
-    _argc: usize,
-    _argv: *const *const char,
+    argc: usize,
+    argv: *const *const char,
     _envp: *const *const char,
     ram_size: usize,
 ) -> ! {
@@ -53,8 +58,17 @@ pub extern "C" fn kernel_init(
     logging::init();
     info!("MOS-Rust started!");
     exception::init();
+    let hz = boot_arg(argc, argv, "hz").map_or(Ok(DEFAULT_HZ), str::parse);
+    clock::init(hz.unwrap_or(0));
+    tty::init();
     mm::init(ram_size);
     pm::init();
+    #[cfg(feature = "gdb_stub")]
+    if boot_arg(argc, argv, "gdb") == Some("1") {
+        gdb::init();
+        info!("gdb: waiting for GDB on the second serial line");
+        gdb::breakpoint();
+    }
 
     // test6_1 pipe tasks
     // env_create!(testptelibrary, "../mos_exec/testptelibrary.b");
@@ -72,6 +86,34 @@ pub extern "C" fn kernel_init(
     schedule(true);
 }
 
+/// Look up key in the boot arguments, given as space separated key=value pairs
+///
+/// # Returns
+///
+/// The value of the last pair with that key, None if there is none
+fn boot_arg(argc: usize, argv: *const *const char, key: &str) -> Option<&'static str> {
+    if argv.is_null() {
+        return None;
+    }
+    let mut value = None;
+    for i in 0..argc {
+        let arg = unsafe { *argv.add(i) };
+        if arg.is_null() {
+            continue;
+        }
+        let Ok(arg) = unsafe { CStr::from_ptr(arg as *const c_char) }.to_str() else {
+            continue;
+        };
+        value = arg
+            .split(' ')
+            .filter_map(|pair| pair.split_once('='))
+            .rfind(|&(k, _)| k == key)
+            .map(|(_, v)| v)
+            .or(value);
+    }
+    value
+}
+
 /// Clear the .bss section
 ///
 /// This function clears the `.bss` section of the kernel.
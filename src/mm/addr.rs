@@ -10,7 +10,7 @@
 //! The module also provides conversion functions between these types, as well as arithmetic operations on them.
 
 use super::{
-    get_pagenum,
+    get_lowmem_pagenum,
     layout::{KSEG0, PDSHIFT, PGSHIFT},
 };
 use core::{
@@ -83,14 +83,18 @@ impl PA {
     ///
     /// # Panics
     ///
-    /// Panics if the physical address is beyond the physical memory size
+    /// Panics if the physical address is beyond the physical memory size, or in high
+    /// memory, which is only reached through `kmap`
     ///
     /// # Returns
     ///
     /// The kernel virtual address
     pub fn kaddr(self) -> VA {
         let ppn = PPN::from(self);
-        assert!(ppn.0 < get_pagenum(), "PA::kaddr: Invalid physical address");
+        assert!(
+            ppn.0 < get_lowmem_pagenum(),
+            "PA::kaddr: Invalid physical address"
+        );
         VA(self.0 + KSEG0)
     }
 }
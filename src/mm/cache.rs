@@ -0,0 +1,67 @@
+//! Cache maintenance for instructions written by the kernel.
+//!
+//! The kernel writes instructions, e.g. breakpoints, through its own address of the page,
+//! a KSEG0 or `kmap` address, while they are fetched at another, user, address. The data
+//! cache lines are written back at the kernel address, and the instruction cache lines
+//! are dropped by index at the fetch address: the caches are virtually indexed with ways
+//! larger than a page, so the two addresses may fall on different lines, and a hit
+//! operation on a user address would need a TLB entry of the env.
+
+use super::layout::KSEG0;
+use core::arch::asm;
+
+/// Geometry of one cache, as told by Config1
+struct CacheInfo {
+    line: usize,
+    way_size: usize,
+    ways: usize,
+}
+
+impl CacheInfo {
+    /// Decode the sets, line size and associativity fields of Config1 from shift on
+    fn from_config1(config1: u32, shift: u32) -> Self {
+        let field = |offset: u32| ((config1 >> (shift + offset)) & 0x7) as usize;
+        let line = match field(3) {
+            0 => 0,
+            size => 2 << size,
+        };
+        Self {
+            line,
+            way_size: line * (64 << field(6)),
+            ways: field(0) + 1,
+        }
+    }
+}
+
+fn config1() -> u32 {
+    let config1: u32;
+    unsafe { asm!("mfc0 {}, $16, 1", out(reg) config1) };
+    config1
+}
+
+/// Make the len bytes written at the kernel address kva visible to instruction fetches
+/// at va
+pub fn sync_icache(va: usize, kva: usize, len: usize) {
+    let config1 = config1();
+    let dcache = CacheInfo::from_config1(config1, 7);
+    let icache = CacheInfo::from_config1(config1, 16);
+    if dcache.line != 0 {
+        let start = kva & !(dcache.line - 1);
+        for line in (start..kva + len).step_by(dcache.line) {
+            // Hit_Writeback_Inv_D
+            unsafe { asm!("cache 0x15, 0({})", in(reg) line) };
+        }
+    }
+    unsafe { asm!("sync") };
+    if icache.line != 0 {
+        let start = va & !(icache.line - 1);
+        for line in (start..va + len).step_by(icache.line) {
+            let index = line & (icache.way_size - 1);
+            for way in 0..icache.ways {
+                let addr = KSEG0 + way * icache.way_size + index;
+                // Index_Invalidate_I
+                unsafe { asm!("cache 0x00, 0({})", in(reg) addr) };
+            }
+        }
+    }
+}
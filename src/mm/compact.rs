@@ -0,0 +1,196 @@
+//! Physical memory compaction.
+//!
+//! A contiguous allocation fails once the buddy free lists are fragmented, even with
+//! plenty of free pages. `compact` then looks for an aligned block of the requested size
+//! made up only of free pages and movable user pages, migrates those user pages to
+//! pages outside the block and frees the block, letting its buddies coalesce.
+//!
+//! A user page is movable if its `ref_count` is 1 and it is a small page: its single
+//! pte, found by walking the page directories of all envs, is then the only reference
+//! to update.
+//!
+//! Compaction runs when the page allocator is short of blocks, so it allocates nothing
+//! from the kernel heap: its bitmaps are static and only cover low memory, and free
+//! pages it must hold are chained through their own first word.
+
+use super::{
+    addr::PPN,
+    get_lowmem_pagenum, get_pagenum,
+    highmem::copy_page,
+    layout::{LOWMEM_SIZE, PAGE_SIZE, PDMAP, UTEMP, UTOP},
+    map::{PageSize, Pte},
+    page::{page_alloc_high, page_dealloc, page_free_bitmap, page_inc_ref, try_recycle, Page},
+    tlb::tlb_invalidate,
+    VA,
+};
+use crate::{
+    mutex::Mutex,
+    pm::{ENV_MANAGER, NENV},
+};
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::trace;
+
+/// Set while a compaction pass runs, allocations it causes must not compact again
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+
+/// Words of a bitmap over the pages of low memory
+const LOWMEM_WORDS: usize = LOWMEM_SIZE / PAGE_SIZE / 32;
+/// Free pages of low memory, only used while `COMPACTING` is set
+static mut FREE_MAP: [u32; LOWMEM_WORDS] = [0; LOWMEM_WORDS];
+/// Movable pages of low memory, only used while `COMPACTING` is set
+static mut MOVABLE_MAP: [u32; LOWMEM_WORDS] = [0; LOWMEM_WORDS];
+
+/// Marks the end of the chain of held pages
+const NO_PAGE: usize = usize::MAX;
+
+/// Check bit i of bitmap
+fn test_bit(bitmap: &[u32], i: usize) -> bool {
+    bitmap[i / 32] & (1 << (i % 32)) != 0
+}
+
+/// Call f with the asid, virtual address and pte of every valid user mapping of all envs
+fn for_each_user_pte(mut f: impl FnMut(usize, VA, &mut Pte)) {
+    for pos in 0..NENV {
+        let Some(env) = ENV_MANAGER.lock().env_in_use(pos) else {
+            continue;
+        };
+        let pgdir = env.pgdir();
+        let mut va = UTEMP;
+        while va < UTOP {
+            match pgdir.walk(VA(va), false) {
+                Ok(Some(pte)) => {
+                    if pte.is_valid() {
+                        f(env.asid, VA(va), pte);
+                    }
+                    va += PAGE_SIZE;
+                }
+                // skip the whole page table
+                _ => va = (va & !(PDMAP - 1)) + PDMAP,
+            }
+        }
+    }
+}
+
+/// Check if the page mapped by pte can be migrated
+fn movable(pte: &Pte) -> bool {
+    let page = Page::new(pte.ppn());
+    // device memory has no page to move
+    pte.ppn().0 < get_pagenum()
+        && pte.page_size() == PageSize::Small
+        && page.ref_count() == 1
+        && !page.is_kernel_image()
+}
+
+/// Find the aligned block of size pages that needs the fewest migrations
+///
+/// # Returns
+///
+/// The first ppn of the block, None if every block holds an unmovable page
+fn select_block(size: usize, free: &[u32], movable: &[u32]) -> Option<PPN> {
+    let mut best: Option<(usize, usize)> = None;
+    // only low memory is handed out in contiguous blocks
+    for base in (0..get_lowmem_pagenum() - size + 1).step_by(size) {
+        let mut moves = 0;
+        let usable = (base..base + size).all(|ppn| {
+            if test_bit(movable, ppn) {
+                moves += 1;
+            }
+            test_bit(free, ppn) || test_bit(movable, ppn)
+        });
+        if usable && best.is_none_or(|(_, fewest)| moves < fewest) {
+            best = Some((base, moves));
+        }
+    }
+    best.map(|(base, _)| PPN(base))
+}
+
+/// Move the page mapped by pte at va to dst
+fn migrate(asid: usize, va: VA, pte: &mut Pte, dst: Page) {
+    let src = Page::new(pte.ppn());
+    copy_page(src, dst);
+    *pte = Pte::new(dst.ppn(), pte.flags());
+    page_inc_ref(dst);
+    tlb_invalidate(asid, va);
+    try_recycle(src);
+}
+
+/// Free an aligned block of size pages by migrating user pages out of it
+fn compact_block(size: usize) -> bool {
+    // only one pass runs at a time, see `compact`
+    let (free, movable_map) = unsafe {
+        (
+            &mut *addr_of_mut!(FREE_MAP),
+            &mut *addr_of_mut!(MOVABLE_MAP),
+        )
+    };
+    page_free_bitmap(free);
+    movable_map.fill(0);
+    for_each_user_pte(|_, _, pte| {
+        let ppn = pte.ppn().0;
+        if ppn < get_lowmem_pagenum() && movable(pte) {
+            movable_map[ppn / 32] |= 1 << (ppn % 32);
+        }
+    });
+    let Some(base) = select_block(size, free, movable_map) else {
+        return false;
+    };
+    let block = base.0..base.0 + size;
+
+    // pages move to high memory if there is any, free pages inside the block handed out
+    // as destinations are held until the end
+    let mut held = NO_PAGE;
+    let mut moved = 0;
+    let mut complete = true;
+    for_each_user_pte(|asid, va, pte| {
+        if !complete || !block.contains(&pte.ppn().0) || !movable(pte) {
+            return;
+        }
+        let dst = loop {
+            match page_alloc_high(false) {
+                Some(page) if block.contains(&page.ppn().0) => {
+                    // a page of the block is in low memory, chain it through its first word
+                    unsafe { *(page.kaddr().0 as *mut usize) = held };
+                    held = page.ppn().0;
+                }
+                other => break other,
+            }
+        };
+        let Some(dst) = dst else {
+            complete = false;
+            return;
+        };
+        migrate(asid, va, pte, dst);
+        moved += 1;
+    });
+    while held != NO_PAGE {
+        let page = Page::new(PPN(held));
+        held = unsafe { *(page.kaddr().0 as *const usize) };
+        page_dealloc(page);
+    }
+    trace!(
+        "Compaction of {} pages at {:?}: {} pages moved{}.",
+        size,
+        base,
+        moved,
+        if complete { "" } else { ", out of memory" }
+    );
+    complete
+}
+
+/// Try to make room for a contiguous allocation of size pages
+///
+/// # Returns
+///
+/// `true` if a block of that size has been freed
+pub fn compact(size: usize) -> bool {
+    let size = size.next_power_of_two();
+    if size == 1 || size > get_lowmem_pagenum() || COMPACTING.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    let freed = compact_block(size);
+    COMPACTING.store(false, Ordering::Relaxed);
+    freed
+}
@@ -0,0 +1,293 @@
+//! Deduplication of identical user pages.
+//!
+//! While enabled, a scanner running on every timer tick walks the user mappings of all
+//! envs a batch at a time and hashes the content of private pages. Pages with the same
+//! content are merged into one stable page, mapped copy-on-write into every address
+//! space it came from whatever its permissions were, so the first write to it splits it
+//! again through the usual copy-on-write fault.
+//!
+//! As in the swap clock, a page is a candidate only if its `ref_count` is 1 and it is
+//! not `SHARED`: shared memory must stay the same page for all its users. A candidate
+//! is first remembered by its hash, and merged when a second page with the same content
+//! is found. Candidates are forgotten after each full scan, since their content may
+//! change at any time.
+//!
+//! The scanner holds a reference to every stable page, which it drops once it is the
+//! only one left.
+
+use super::{
+    addr::PPN,
+    highmem::kmap,
+    layout::{PteFlags, PAGE_SIZE, PDMAP, UTEMP, UTOP},
+    map::{PageDirectory, PageSize},
+    page::{page_inc_ref, try_recycle, Page},
+    VA,
+};
+use crate::{
+    mutex::{FakeLock, Mutex},
+    pm::{ENV_MANAGER, NENV},
+};
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use log::info;
+
+/// Pages examined on each timer tick
+const DEDUP_BATCH: usize = 64;
+
+/// Deduplication state reported to user space by `sys_dedup_op`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DedupStats {
+    /// 1 if the scanner is running
+    pub enabled: u32,
+    /// Stable pages, each backing several identical user pages
+    pub stable_pages: u32,
+    /// User mappings of stable pages
+    pub merged_pages: u32,
+    /// Pages saved, that is mappings beyond the first of each stable page
+    pub pages_saved: u32,
+    /// Pages examined since the scanner was enabled
+    pub scanned: u32,
+    /// Completed scans over all envs
+    pub full_scans: u32,
+}
+
+/// A page seen once, waiting for a twin
+#[derive(Clone, Copy)]
+struct Candidate {
+    pos: usize,
+    va: usize,
+    ppn: PPN,
+}
+
+/// Scanner state
+struct DedupManager {
+    enabled: bool,
+    /// Merged pages by content hash
+    stable: BTreeMap<u64, Page>,
+    /// Candidates of the current scan by content hash
+    unstable: BTreeMap<u64, Candidate>,
+    /// Env position the scanner points to
+    hand_env: usize,
+    /// Virtual address the scanner points to
+    hand_va: usize,
+    scanned: usize,
+    full_scans: usize,
+}
+
+impl DedupManager {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            stable: BTreeMap::new(),
+            unstable: BTreeMap::new(),
+            hand_env: 0,
+            hand_va: UTEMP,
+            scanned: 0,
+            full_scans: 0,
+        }
+    }
+
+    /// Drop stable pages no user maps anymore, and all candidates
+    fn start_scan(&mut self) {
+        self.stable.retain(|_, &mut page| {
+            if page.ref_count() > 1 {
+                return true;
+            }
+            try_recycle(page);
+            false
+        });
+        self.unstable.clear();
+    }
+
+    /// Release every stable page and candidate
+    fn clear(&mut self) {
+        self.stable.values().for_each(|&page| try_recycle(page));
+        self.stable.clear();
+        self.unstable.clear();
+    }
+}
+
+lazy_static! {
+    static ref DEDUP_MANAGER: FakeLock<DedupManager> = FakeLock::new(DedupManager::new());
+}
+
+/// Check if pages a and b have the same content
+fn same_content(a: Page, b: Page) -> bool {
+    kmap(a).bytes() == kmap(b).bytes()
+}
+
+/// FNV-1a hash of the content of page
+fn page_hash(page: Page) -> u64 {
+    kmap(page)
+        .bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
+
+/// Acquire the page at va of pgdir if it may be merged
+fn candidate_page(pgdir: PageDirectory, va: usize) -> Option<Page> {
+    let (pte, page) = pgdir.lookup(VA(va))?;
+    let mergeable = !pte.flags().intersects(PteFlags::SHARED | PteFlags::ZERO)
+        && pte.page_size() == PageSize::Small
+        && page.ref_count() == 1
+        && !page.is_kernel_image();
+    mergeable.then_some(page)
+}
+
+/// Map va of pgdir to the stable page, copy-on-write
+///
+/// Read-only mappings become copy-on-write too: `protect_pages` and `sys_mem_map`
+/// never let a copy-on-write page be written in place.
+fn merge_into(pgdir: PageDirectory, asid: usize, va: usize, stable: Page) -> bool {
+    let Some((pte, _)) = pgdir.lookup(VA(va)) else {
+        return false;
+    };
+    let flags = (pte.flags() - PteFlags::D) | PteFlags::COW;
+    pgdir.insert(asid, stable, VA(va), flags).is_ok()
+}
+
+/// Try to merge the candidate page at va of pgdir
+fn scan_page(manager: &mut DedupManager, pos: usize, pgdir: PageDirectory, asid: usize, va: usize) {
+    let Some(page) = candidate_page(pgdir, va) else {
+        return;
+    };
+    manager.scanned += 1;
+    let hash = page_hash(page);
+
+    if let Some(&stable) = manager.stable.get(&hash) {
+        if same_content(stable, page) {
+            merge_into(pgdir, asid, va, stable);
+        }
+        return;
+    }
+
+    let Some(twin) = manager.unstable.get(&hash).copied() else {
+        manager.unstable.insert(
+            hash,
+            Candidate {
+                pos,
+                va,
+                ppn: page.ppn(),
+            },
+        );
+        return;
+    };
+    // the twin may have been unmapped, written or shared since it was seen
+    let Some(twin_env) = ENV_MANAGER.lock().env_in_use(twin.pos) else {
+        manager.unstable.insert(
+            hash,
+            Candidate {
+                pos,
+                va,
+                ppn: page.ppn(),
+            },
+        );
+        return;
+    };
+    let twin_pgdir = twin_env.pgdir();
+    match candidate_page(twin_pgdir, twin.va) {
+        Some(twin_page)
+            if twin_page.ppn() == twin.ppn
+                && twin_page.ppn() != page.ppn()
+                && same_content(twin_page, page) =>
+        {
+            manager.unstable.remove(&hash);
+            page_inc_ref(twin_page);
+            if !merge_into(twin_pgdir, twin_env.asid, twin.va, twin_page) {
+                try_recycle(twin_page);
+                return;
+            }
+            manager.stable.insert(hash, twin_page);
+            merge_into(pgdir, asid, va, twin_page);
+        }
+        _ => {
+            manager.unstable.insert(
+                hash,
+                Candidate {
+                    pos,
+                    va,
+                    ppn: page.ppn(),
+                },
+            );
+        }
+    }
+}
+
+/// Scan the next batch of user pages, called on every timer tick
+pub fn tick() {
+    let mut manager = DEDUP_MANAGER.lock();
+    if !manager.enabled {
+        return;
+    }
+    let mut budget = DEDUP_BATCH;
+    while budget > 0 {
+        let (pos, start) = (manager.hand_env, manager.hand_va);
+        if let Some(env) = ENV_MANAGER.lock().env_in_use(pos) {
+            let pgdir = env.pgdir();
+            let mut va = start;
+            while va < UTOP && budget > 0 {
+                if !matches!(pgdir.walk(VA(va), false), Ok(Some(_))) {
+                    // skip the whole page table
+                    va = (va & !(PDMAP - 1)) + PDMAP;
+                    continue;
+                }
+                scan_page(&mut manager, pos, pgdir, env.asid, va);
+                va += PAGE_SIZE;
+                budget -= 1;
+            }
+            if va < UTOP {
+                manager.hand_va = va;
+                return;
+            }
+        }
+        manager.hand_env = (pos + 1) % NENV;
+        manager.hand_va = UTEMP;
+        if manager.hand_env == 0 {
+            manager.full_scans += 1;
+            manager.start_scan();
+            // a full scan over idle envs ends the batch
+            return;
+        }
+    }
+}
+
+/// Start or stop the scanner
+///
+/// Stopping it forgets all stable pages, pages merged so far stay shared until written.
+pub fn set_enabled(enabled: bool) {
+    let mut manager = DEDUP_MANAGER.lock();
+    if manager.enabled == enabled {
+        return;
+    }
+    if !enabled {
+        manager.clear();
+    }
+    *manager = DedupManager {
+        enabled,
+        ..DedupManager::new()
+    };
+    info!(
+        "Page deduplication {}.",
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
+
+/// Acquire current deduplication statistics
+pub fn stats() -> DedupStats {
+    let manager = DEDUP_MANAGER.lock();
+    // the scanner's own reference is not a mapping
+    let mappings = || manager.stable.values().map(|page| page.ref_count() - 1);
+    DedupStats {
+        enabled: manager.enabled as u32,
+        stable_pages: manager.stable.len() as u32,
+        merged_pages: mappings().map(u32::from).sum(),
+        pages_saved: mappings()
+            .map(|count| u32::from(count.saturating_sub(1)))
+            .sum(),
+        scanned: manager.scanned as u32,
+        full_scans: manager.full_scans as u32,
+    }
+}
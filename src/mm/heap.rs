@@ -1,24 +1,243 @@
 //! Heap allocator.
 //!
 //! This module provides a heap allocator implementation for managing dynamic memory allocation.
-//! It includes functions for initializing the allocator and performing a heap test.
+//! It includes functions for initializing the allocator and reporting heap usage.
 //!
 //! # Note
 //!
-//! This implementation uses a fixed-size kernel heap of 1 MiB.
+//! The kernel heap starts as a fixed-size static arena of 1 MiB. When it runs out, new
+//! arenas are requested from the page allocator, and arenas that become empty are given
+//! back once heap usage drops.
+//!
+//! Small allocations are served from the kmalloc slab caches in `slab.rs` once the page
+//! allocator is available, only larger ones (or early ones) go to the heap arenas.
 
+use super::{
+    layout::PAGE_SIZE,
+    page::{
+        page_alloc_contiguous_no_compact, page_allocator_busy, page_dealloc_contiguous,
+        page_dec_ref, page_inc_ref, Page,
+    },
+    slab::{kfree, kmalloc},
+    PPN, VA,
+};
+use crate::platform::halt;
 use allocator::Allocator;
-use log::info;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{addr_of, addr_of_mut, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::{error, info};
 
 // 1MiB
 const KERNEL_HEAP_SIZE: usize = 0x100_000;
+/// Pages requested from the page allocator for each new arena, 256 KiB
+const ARENA_PAGES: usize = 64;
+/// Maximum number of heap arenas, including the static one
+const MAX_ARENAS: usize = 16;
 
-// For 64MiB of memory, it will take 26 bits to represent each byte.
-// So 32 bits are enough.
-#[global_allocator]
-static ALLOCATOR: Allocator<32> = Allocator::new();
 static mut KERNEL_HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+/// A contiguous memory region managed by its own buddy allocator
+struct Arena {
+    // For 64MiB of memory, it will take 26 bits to represent each byte.
+    // So 32 bits are enough.
+    heap: Allocator<32>,
+    base: usize,
+    size: usize,
+    in_use: usize,
+}
+
+impl Arena {
+    const fn empty() -> Self {
+        Self {
+            heap: Allocator::new(),
+            base: 0,
+            size: 0,
+            in_use: 0,
+        }
+    }
+
+    const fn contains(&self, addr: usize) -> bool {
+        self.size != 0 && addr >= self.base && addr < self.base + self.size
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_ARENA: Arena = Arena::empty();
+/// Heap arenas, `ARENAS[0]` is the static kernel heap and is never released
+static mut ARENAS: [Arena; MAX_ARENAS] = [EMPTY_ARENA; MAX_ARENAS];
+
+static HEAP_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static HEAP_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Kernel heap usage
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes managed by all arenas
+    pub capacity: usize,
+    /// Bytes currently allocated from the arenas
+    pub in_use: usize,
+    /// Highest value of `in_use` since boot
+    pub peak: usize,
+    /// Number of arenas in use
+    pub arenas: usize,
+}
+
+/// Global allocator of the kernel, dispatching between the slab caches and the heap
+struct KernelAllocator;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = kmalloc(layout) {
+            return ptr.as_ptr();
+        }
+        if let Some(ptr) = heap_alloc(layout) {
+            return ptr.as_ptr();
+        }
+        if grow(layout) {
+            if let Some(ptr) = heap_alloc(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        out_of_memory(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(arena) = arena_of(ptr as usize) {
+            arena.heap.dealloc(ptr, layout);
+            arena.in_use -= layout.size();
+            HEAP_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+            trim();
+        } else {
+            kfree(NonNull::new_unchecked(ptr), layout);
+        }
+    }
+}
+
+/// Find the arena containing `addr`
+fn arena_of(addr: usize) -> Option<&'static mut Arena> {
+    unsafe { (*addr_of_mut!(ARENAS)).iter_mut() }.find(|arena| arena.contains(addr))
+}
+
+/// Allocate from the first arena able to serve `layout`
+fn heap_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    for i in 0..MAX_ARENAS {
+        let arena = unsafe { &mut (*addr_of_mut!(ARENAS))[i] };
+        if arena.size == 0 {
+            continue;
+        }
+        if let Some(ptr) = NonNull::new(unsafe { arena.heap.alloc(layout) }) {
+            arena.in_use += layout.size();
+            let in_use = HEAP_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            HEAP_PEAK.fetch_max(in_use, Ordering::Relaxed);
+            return Some(ptr);
+        }
+    }
+    None
+}
+
+/// Add a new arena large enough for `layout`, backed by contiguous pages
+///
+/// # Returns
+///
+/// `true` if a new arena was added
+fn grow(layout: Layout) -> bool {
+    if page_allocator_busy() {
+        // the page allocator itself is allocating from the heap
+        return false;
+    }
+    let Some(slot) =
+        (1..MAX_ARENAS).find(|&i| unsafe { (*addr_of!(ARENAS))[i].size } == 0)
+    else {
+        return false;
+    };
+    // the buddy allocator needs room for splitting, so ask for twice the request
+    let pages = ARENA_PAGES.max(((layout.size() + layout.align()) * 2).div_ceil(PAGE_SIZE));
+    let pages = pages.next_power_of_two();
+    // no reference to the arenas is held here, the page allocator may use the heap
+    let Some(page) = page_alloc_contiguous_no_compact(false, pages) else {
+        return false;
+    };
+    for i in 0..pages {
+        page_inc_ref(Page::new(page.ppn() + i));
+    }
+    let base = page.kaddr().0;
+    let size = pages * PAGE_SIZE;
+    let arena = unsafe { &mut (*addr_of_mut!(ARENAS))[slot] };
+    *arena = Arena::empty();
+    unsafe { arena.heap.lock().add_size(base, size) };
+    arena.base = base;
+    arena.size = size;
+    let capacity = HEAP_CAPACITY.fetch_add(size, Ordering::Relaxed) + size;
+    info!(
+        "Kernel heap grown by {} KiB to {} KiB.",
+        size / 1024,
+        capacity / 1024
+    );
+    true
+}
+
+/// Give empty arenas back to the page allocator once heap usage drops
+/// below half of the remaining capacity.
+fn trim() {
+    if page_allocator_busy() {
+        return;
+    }
+    for i in 1..MAX_ARENAS {
+        let arena = unsafe { &mut (*addr_of_mut!(ARENAS))[i] };
+        if arena.size == 0 || arena.in_use != 0 {
+            continue;
+        }
+        let remaining = HEAP_CAPACITY.load(Ordering::Relaxed) - arena.size;
+        if HEAP_IN_USE.load(Ordering::Relaxed) * 2 > remaining {
+            continue;
+        }
+        let (base, size) = (arena.base, arena.size);
+        *arena = Arena::empty();
+        HEAP_CAPACITY.fetch_sub(size, Ordering::Relaxed);
+        let ppn = PPN::from(VA(base).paddr());
+        for j in 0..size / PAGE_SIZE {
+            page_dec_ref(Page::new(ppn + j));
+        }
+        page_dealloc_contiguous(Page::new(ppn), size / PAGE_SIZE);
+        info!("Kernel heap shrunk by {} KiB.", size / 1024);
+    }
+}
+
+/// Report a failed kernel allocation and halt
+fn out_of_memory(layout: Layout) -> ! {
+    let stats = stats();
+    error!(
+        "Kernel out of memory: failed to allocate {} bytes (align {}), heap usage {}/{} KiB in {} arenas, peak {} KiB",
+        layout.size(),
+        layout.align(),
+        stats.in_use / 1024,
+        stats.capacity / 1024,
+        stats.arenas,
+        stats.peak / 1024
+    );
+    // the panic handler allocates, so stop here
+    halt()
+}
+
+/// Acquire current kernel heap usage
+pub fn stats() -> HeapStats {
+    HeapStats {
+        capacity: HEAP_CAPACITY.load(Ordering::Relaxed),
+        in_use: HEAP_IN_USE.load(Ordering::Relaxed),
+        peak: HEAP_PEAK.load(Ordering::Relaxed),
+        arenas: unsafe { (*addr_of!(ARENAS)).iter() }
+            .filter(|arena| arena.size != 0)
+            .count(),
+    }
+}
+
 /// Initialize the heap allocator.
 ///
 /// This function initializes the heap allocator by adding the kernel heap memory range to the allocator.
@@ -26,10 +245,12 @@ static mut KERNEL_HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 /// This function should be called only once.
 pub fn init() {
     unsafe {
-        ALLOCATOR
-            .lock()
-            .add_size(KERNEL_HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
+        let arena = &mut (*addr_of_mut!(ARENAS))[0];
+        arena.heap.lock().add_size(KERNEL_HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
+        arena.base = KERNEL_HEAP.as_ptr() as usize;
+        arena.size = KERNEL_HEAP_SIZE;
     }
+    HEAP_CAPACITY.store(KERNEL_HEAP_SIZE, Ordering::Relaxed);
     info!(
         "Initialized {} KiB of kernel heap.",
         KERNEL_HEAP_SIZE / 1024
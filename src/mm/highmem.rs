@@ -0,0 +1,114 @@
+//! High memory, the physical pages above the KSEG0 window.
+//!
+//! The kernel only reaches the first `LOWMEM_SIZE` bytes of RAM through KSEG0, the
+//! rest of it lies above the Malta PCI/IO hole, at `HIGHMEM_PHYS_BASE`. Pages above
+//! are handed out as user pages by `page_alloc_high`: user space reaches them through
+//! its own mappings, and the kernel maps them for a moment with `kmap` whenever it
+//! clears, copies or fills one.
+//!
+//! `kmap` uses a window of `KMAP_SLOTS` pages at `KMAP_START`, with global mappings in
+//! the page directory of `kseg2`, so a kernel TLB miss on them is refilled the same
+//! way.
+
+use super::{
+    addr::VA,
+    kseg2,
+    layout::{PteFlags, KMAP_SLOTS, KMAP_START, PAGE_SIZE},
+    map::Pte,
+    page::Page,
+    tlb::tlb_invalidate,
+};
+use core::{
+    slice,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Bitmap of the kmap slots in use
+static SLOTS: AtomicU32 = AtomicU32::new(0);
+
+/// Create the page table of the kmap window
+///
+/// `kmap` is used by the page allocator itself, so it must never allocate a page.
+pub fn init() {
+    let pgdir = kseg2::pgdir();
+    pgdir
+        .walk(VA(KMAP_START), true)
+        .expect("highmem: failed to init kmap page table");
+}
+
+/// Temporary kernel mapping of a page, unmapped when dropped
+pub struct KMap {
+    va: VA,
+    /// Slot of the mapping, None for a low memory page reached through KSEG0
+    slot: Option<usize>,
+}
+
+impl KMap {
+    /// Acquire the kernel virtual address of the page
+    pub const fn va(&self) -> VA {
+        self.va
+    }
+
+    /// Kernel view of the content of the page
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.va.as_ptr::<u8>(), PAGE_SIZE) }
+    }
+
+    /// Mutable kernel view of the content of the page
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.va.as_mut_ptr::<u8>(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for KMap {
+    fn drop(&mut self) {
+        let Some(slot) = self.slot else {
+            return;
+        };
+        let pgdir = kseg2::pgdir();
+        if let Ok(Some(pte)) = pgdir.walk(self.va, false) {
+            *pte = Pte::empty();
+        }
+        // global entries match any asid
+        tlb_invalidate(0, self.va);
+        SLOTS.fetch_and(!(1 << slot), Ordering::Relaxed);
+    }
+}
+
+/// Map page into the kernel address space
+///
+/// Low memory pages are already reached through KSEG0 and take no slot.
+///
+/// # Panics
+///
+/// Panics if all `KMAP_SLOTS` slots are in use
+pub fn kmap(page: Page) -> KMap {
+    if !page.is_highmem() {
+        return KMap {
+            va: page.kaddr(),
+            slot: None,
+        };
+    }
+    let slot = (!SLOTS.load(Ordering::Relaxed)).trailing_zeros() as usize;
+    assert!(slot < KMAP_SLOTS, "kmap: out of slots");
+    SLOTS.fetch_or(1 << slot, Ordering::Relaxed);
+    let va = VA(KMAP_START + slot * PAGE_SIZE);
+    let pgdir = kseg2::pgdir();
+    let Ok(Some(pte)) = pgdir.walk(va, false) else {
+        unreachable!("kmap: window not initialized");
+    };
+    *pte = Pte::new(
+        page.ppn(),
+        PteFlags::V | PteFlags::D | PteFlags::G | PteFlags::Cacheable,
+    );
+    KMap {
+        va,
+        slot: Some(slot),
+    }
+}
+
+/// Copy the content of page src to page dst
+pub fn copy_page(src: Page, dst: Page) {
+    let src = kmap(src);
+    kmap(dst).bytes_mut().copy_from_slice(src.bytes());
+}
@@ -0,0 +1,49 @@
+//! Kernel mappings in KSEG2.
+//!
+//! The kernel reaches low memory through KSEG0, where virtual addresses follow physical
+//! ones. KSEG2 is mapped through the TLB instead, with a page directory of the kernel's
+//! own. These mappings are global, the same in every address space, and kernel TLB
+//! misses on them are refilled from that page directory. They are made by `vmalloc` and
+//! `kmap`.
+
+use super::{
+    addr::VA,
+    layout::{PteFlags, PAGE_SIZE},
+    map::{PageDirectory, PageSize, Pte},
+};
+use crate::mutex::{FakeLock, Mutex};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref KSEG2_PGDIR: FakeLock<PageDirectory> = FakeLock::new(PageDirectory::empty());
+}
+
+/// Create the page directory of KSEG2
+pub fn init() {
+    let (pgdir, _) = PageDirectory::init().expect("kseg2: failed to init pgdir");
+    *KSEG2_PGDIR.lock() = pgdir;
+}
+
+/// Acquire the page directory of KSEG2
+pub fn pgdir() -> PageDirectory {
+    *KSEG2_PGDIR.lock()
+}
+
+/// EntryLo pair and PageMask for a TLB entry mapping va of KSEG2
+///
+/// # Returns
+///
+/// None if va is not mapped
+pub fn tlb_entry(va: VA) -> Option<[u32; 3]> {
+    let pgdir = pgdir();
+    let base = va.0 & !(2 * PAGE_SIZE - 1);
+    let even = *pgdir.walk(VA(base), false).ok()??;
+    let odd = *pgdir.walk(VA(base + PAGE_SIZE), false).ok()??;
+    let pte = if va.0 & PAGE_SIZE == 0 { even } else { odd };
+    if !pte.is_valid() {
+        return None;
+    }
+    // an entry is only global if both halves are, even when one of them is unmapped
+    let global = |pte: Pte| Pte(pte.0 | PteFlags::G.bits()).as_entrylo();
+    Some([global(even), global(odd), PageSize::Small.page_mask()])
+}
@@ -23,6 +23,8 @@
 //! It includes the following sections:
 //!
 //! - `KSEG2`: Kernel segment 2.
+//! - `VMALLOC_START`, `VMALLOC_END`: Kernel virtual memory area.
+//! - `KMAP_START`: Temporary kernel mappings of high memory pages.
 //! - `KSEG1`: Kernel segment 1.
 //! - `KSEG0`: Kernel segment 0.
 //! - `KSTACKTOP`: Kernel stack top.
@@ -64,7 +66,7 @@ pub const PTE_HARDFLAG_SHIFT: usize = 6;
 
 bitflags! {
     /// Pte flag definations
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
     pub struct PteFlags: usize {
         // The 6 bits below are those stored in cp0.entry_lo
         /// Global bit
@@ -93,11 +95,39 @@ bitflags! {
         const COW = 0x1;
         /// Read-only bit
         const SHARED = 0x2;
+        /// Left to user space, e.g. the file system server marks dirty blocks with it
+        const USER = 0x4;
+        /// Mapped to the shared zero page, replaced by a private page on the first write
+        const ZERO = 0x8;
+        /// Low bit of the size of a large page, see `PageSize`
+        const PGSZ0 = 0x10;
+        /// High bit of the size of a large page, see `PageSize`
+        const PGSZ1 = 0x20;
+    }
+}
+
+impl PteFlags {
+    /// Decode a perm passed by user space, dropping the soft bits of the kernel
+    pub const fn from_user(perm: u32) -> Self {
+        const USER_PERM: PteFlags = PteFlags::G
+            .union(PteFlags::V)
+            .union(PteFlags::D)
+            .union(PteFlags::C0)
+            .union(PteFlags::C1)
+            .union(PteFlags::C2)
+            .union(PteFlags::COW)
+            .union(PteFlags::SHARED)
+            .union(PteFlags::USER);
+        Self::from_bits_truncate(perm as usize).intersection(USER_PERM)
     }
 }
 /*
  o     4G ----------->  +----------------------------+------------0x100000000
  o                      |       ...                  |  kseg2
+ o                      +----------------------------+------------0xe002 0000
+ o                      |           kmap             |
+ o      KMAP_START ---> +----------------------------+------------0xe000 0000
+ o                      |          vmalloc           |
  o      KSEG2    -----> +----------------------------+------------0xc000 0000
  o                      |          Devices           |  kseg1
  o      KSEG1    -----> +----------------------------+------------0xa000 0000
@@ -145,7 +175,24 @@ bitflags! {
 pub const KSEG0: usize = 0x8000_0000;
 /// KSEG1 address
 pub const KSEG1: usize = 0xa000_0000;
-// pub const KSEG2: usize = 0xc000_0000;
+/// KSEG2 address
+pub const KSEG2: usize = 0xc000_0000;
+/// Start of the kernel virtual memory area, see `vmalloc`
+pub const VMALLOC_START: usize = KSEG2;
+/// End of the kernel virtual memory area
+pub const VMALLOC_END: usize = 0xe000_0000;
+/// Start of the temporary kernel mappings of high memory pages, see `kmap`
+pub const KMAP_START: usize = VMALLOC_END;
+/// Number of pages that can be temporarily mapped at once
+pub const KMAP_SLOTS: usize = 32;
+/// Bytes of physical memory reached through KSEG0, pages above are high memory
+///
+/// On Malta, physical 256 MiB to 512 MiB is the PCI and IO space, not RAM.
+pub const LOWMEM_SIZE: usize = 0x1000_0000;
+/// Physical address where Malta shows the whole RAM again, above the IO hole
+///
+/// High memory pages are mapped at their offset from here.
+pub const HIGHMEM_PHYS_BASE: usize = 0x8000_0000;
 
 const_export_usize!(KSTACKTOP, 0x80400000);
 // pub const KERNBASE: usize = 0x8002_0000;
@@ -166,6 +213,12 @@ pub const UXSTACKTOP: usize = UTOP;
 
 /// USTACKTOP address
 pub const USTACKTOP: usize = UTOP - 2 * PTMAP;
+/// Default maximum size of the user stack below USTACKTOP
+pub const USTACKSIZE: usize = 0x0010_0000;
+/// Upper bound of the maximum user stack size an env may ask for
+pub const USTACKSIZE_MAX: usize = 0x0400_0000;
+/// Lowest address picked by `sys_mmap` when no address is given
+pub const UMMAP: usize = 0x7000_0000;
 /// UTEXT address
 pub const UTEXT: usize = PDMAP;
 /// UCOW address
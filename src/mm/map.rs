@@ -1,14 +1,106 @@
 //! Implementation of page entry table, page directory table, and related functions.
 
 use crate::error::MosError;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use super::{
     addr::{PA, PPN, VA},
-    layout::{PteFlags, PTE_HARDFLAG_SHIFT},
-    page::{page_alloc, page_inc_ref, try_recycle, Page},
-    tlb::tlb_invalidate,
+    highmem::copy_page,
+    layout::{
+        PteFlags, HIGHMEM_PHYS_BASE, LOWMEM_SIZE, NASID, PAGE_SIZE, PGSHIFT, PTE_HARDFLAG_SHIFT,
+        UTOP,
+    },
+    page::{page_alloc, page_dealloc, page_inc_ref, try_recycle, Page},
+    swap::{alloc_user_page, free_slot},
+    tlb::{tlb_flush, tlb_invalidate},
 };
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_RSS: AtomicUsize = AtomicUsize::new(0);
+/// Resident user pages of each address space, by asid
+static RSS: [AtomicUsize; NASID] = [NEW_RSS; NASID];
+
+/// Acquire the number of resident user pages of the address space of asid
+pub fn rss(asid: usize) -> usize {
+    RSS[asid].load(Ordering::Relaxed)
+}
+
+/// Reset the resident page count of asid, for a new address space
+pub fn rss_reset(asid: usize) {
+    RSS[asid].store(0, Ordering::Relaxed);
+}
+
+/// Account a user page at va becoming resident in the address space of asid
+pub fn rss_inc(asid: usize, va: VA) {
+    if va.0 < UTOP {
+        RSS[asid].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Account a user page at va leaving the address space of asid
+pub fn rss_dec(asid: usize, va: VA) {
+    if va.0 < UTOP {
+        RSS[asid].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Size of the pages mapped by a TLB entry
+///
+/// Each TLB entry maps an even and an odd page of the same size, so a large page is
+/// only ever mapped together with its buddy, both halves aligned to the page size.
+/// Every 4 KiB page of a large page still has its own pte, marked with the size.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageSize {
+    /// 4 KiB
+    Small,
+    /// 64 KiB
+    Large64K,
+    /// 1 MiB
+    Large1M,
+    /// 16 MiB
+    Large16M,
+}
+
+impl PageSize {
+    /// Large page sizes, largest first
+    const LARGE: [Self; 3] = [Self::Large16M, Self::Large1M, Self::Large64K];
+
+    /// Decode the size marked in flags
+    pub const fn from_flags(flags: PteFlags) -> Self {
+        match flags.bits() & (PteFlags::PGSZ0.bits() | PteFlags::PGSZ1.bits()) {
+            0 => Self::Small,
+            0x10 => Self::Large64K,
+            0x20 => Self::Large1M,
+            _ => Self::Large16M,
+        }
+    }
+
+    /// Pte flags marking a page of this size
+    pub const fn flags(self) -> PteFlags {
+        match self {
+            Self::Small => PteFlags::empty(),
+            Self::Large64K => PteFlags::PGSZ0,
+            Self::Large1M => PteFlags::PGSZ1,
+            Self::Large16M => PteFlags::PGSZ0.union(PteFlags::PGSZ1),
+        }
+    }
+
+    /// Size in bytes
+    pub const fn bytes(self) -> usize {
+        match self {
+            Self::Small => 0x1000,
+            Self::Large64K => 0x1_0000,
+            Self::Large1M => 0x10_0000,
+            Self::Large16M => 0x100_0000,
+        }
+    }
+
+    /// Value of the CP0 PageMask register for this size
+    pub const fn page_mask(self) -> u32 {
+        ((self.bytes() / PAGE_SIZE - 1) << (PGSHIFT + 1)) as u32
+    }
+}
+
 /// Page table entry
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -54,14 +146,46 @@ impl Pte {
         self.0 |= flags.bits();
     }
 
+    /// Acquire the size of the page this entry is part of
+    pub const fn page_size(self) -> PageSize {
+        PageSize::from_flags(self.flags())
+    }
+
     /// Check if this pte is valid(contains PteFlags::V)
     pub const fn is_valid(self) -> bool {
         self.flags().contains(PteFlags::V)
     }
 
+    /// Construct an entry for a page swapped out to slot
+    ///
+    /// Flags are kept, except that V is cleared, and the ppn field holds slot + 1, so that
+    /// no flag bit is taken from user space.
+    pub fn swapped(slot: usize, flags: PteFlags) -> Self {
+        Self((slot + 1) << 12 | (flags - PteFlags::V).bits())
+    }
+
+    /// Check if this pte refers to a swapped-out page
+    ///
+    /// Only swapped-out entries are invalid with a ppn field.
+    pub const fn is_swapped(self) -> bool {
+        !self.is_valid() && self.0 >> 12 != 0
+    }
+
+    /// Acquire swap slot of a swapped-out entry
+    pub const fn swap_slot(self) -> usize {
+        (self.0 >> 12) - 1
+    }
+
     /// Get entry LO of this Pte
+    ///
+    /// A page of high memory is mapped at its alias above `HIGHMEM_PHYS_BASE`.
     pub const fn as_entrylo(self) -> u32 {
-        self.0 as u32 >> PTE_HARDFLAG_SHIFT
+        let entrylo = self.0 as u32 >> PTE_HARDFLAG_SHIFT;
+        if self.ppn().0 >= LOWMEM_SIZE / PAGE_SIZE {
+            entrylo + ((HIGHMEM_PHYS_BASE / PAGE_SIZE) << PTE_HARDFLAG_SHIFT) as u32
+        } else {
+            entrylo
+        }
     }
 }
 
@@ -136,6 +260,8 @@ impl PageTable {
 
     /// Map the physical page at virtual address va,
     /// the lower 12 bits of pte will be set to flags
+    /// Pages of the kernel image are never mapped writable,
+    /// the zero page is mapped read-only with `PteFlags::ZERO` in place of D
     ///
     /// # Returns
     ///
@@ -143,15 +269,25 @@ impl PageTable {
     /// ``MosError::NoMem`` if page allocation failed
     pub fn insert(self, asid: usize, page: Page, va: VA, flags: PteFlags) -> Result<(), MosError> {
         let ppn = page.ppn();
+        let mut flags = flags;
+        if page.is_kernel_image() {
+            flags -= PteFlags::D;
+        }
+        if !page.is_zero_page() {
+            flags -= PteFlags::ZERO;
+        } else if flags.intersects(PteFlags::D | PteFlags::ZERO) {
+            flags = (flags - PteFlags::D) | PteFlags::ZERO;
+        }
         if let Ok(Some(pte)) = self.walk(va, false) {
-            if pte.flags().contains(PteFlags::V) {
-                if ppn == pte.ppn() {
-                    tlb_invalidate(asid, va);
-                    pte.set_flags(flags | PteFlags::V | PteFlags::Cacheable);
-                    return Ok(());
-                }
-                self.remove(asid, va);
+            if pte.page_size() != PageSize::from_flags(flags) || ppn != pte.ppn() {
+                self.demote(va);
+            }
+            if pte.flags().contains(PteFlags::V) && ppn == pte.ppn() {
+                tlb_invalidate(asid, va);
+                pte.set_flags(flags | PteFlags::V | PteFlags::Cacheable);
+                return Ok(());
             }
+            self.remove(asid, va);
         }
 
         tlb_invalidate(asid, va);
@@ -159,6 +295,7 @@ impl PageTable {
         if let Ok(Some(pte)) = self.walk(va, true) {
             *pte = Pte::new(ppn, flags | PteFlags::V | PteFlags::Cacheable);
             page_inc_ref(page);
+            rss_inc(asid, va);
             Ok(())
         } else {
             Err(MosError::NoMem)
@@ -186,13 +323,174 @@ impl PageTable {
     }
 
     /// Unmap the page at virtual address va
+    /// The swap slot is released if the page is swapped out
     pub fn remove(self, asid: usize, va: VA) {
+        if let Ok(Some(pte)) = self.walk(va, false) {
+            self.demote(va);
+            if pte.is_swapped() {
+                free_slot(*pte);
+                *pte = Pte::empty();
+                return;
+            }
+        }
         if let Some((pte, page)) = self.lookup(va) {
             tlb_invalidate(asid, va);
             try_recycle(page);
             *pte = Pte::empty();
+            rss_dec(asid, va);
+        }
+    }
+
+    /// Give va a private cleared page if it maps the zero page with `PteFlags::ZERO`
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if the mapping was replaced, `Ok(false)` if va does not map the zero
+    /// page writable, `MosError::NoMem` if no page could be allocated.
+    pub fn unshare_zero(self, asid: usize, va: VA) -> Result<bool, MosError> {
+        let flags = match self.lookup(va) {
+            Some((pte, _)) if pte.flags().contains(PteFlags::ZERO) => pte.flags(),
+            _ => return Ok(false),
+        };
+        let page = alloc_user_page(true).ok_or(MosError::NoMem)?;
+        if let Err(err) = self.insert(asid, page, va, (flags - PteFlags::ZERO) | PteFlags::D) {
+            page_dealloc(page);
+            return Err(err);
         }
+        Ok(true)
     }
+
+    /// Resolve a write to the copy-on-write page at va
+    ///
+    /// The page is copied if it is still shared, or made writable in place if this is
+    /// its last mapping.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if va is writable afterwards, `Ok(false)` if va does not map a
+    /// copy-on-write page, `MosError::NoMem` if no page could be allocated.
+    pub fn resolve_cow(self, asid: usize, va: VA) -> Result<bool, MosError> {
+        let (flags, page) = match self.lookup(va) {
+            Some((pte, page)) if pte.flags().contains(PteFlags::COW) => (pte.flags(), page),
+            _ => return Ok(false),
+        };
+        let flags = (flags - PteFlags::COW) | PteFlags::D;
+        if page.ref_count() == 1 && !page.is_kernel_image() && !page.is_zero_page() {
+            return self.insert(asid, page, va, flags).map(|()| true);
+        }
+        let copy = alloc_user_page(false).ok_or(MosError::NoMem)?;
+        copy_page(page, copy);
+        if let Err(err) = self.insert(asid, copy, va, flags) {
+            page_dealloc(copy);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Give va a page of its own, for the kernel to write to on behalf of a debugger
+    ///
+    /// A page mapped elsewhere too, the zero page or a page of the kernel image is
+    /// copied, the mapping keeps its permissions. Pages shared on purpose with
+    /// `PteFlags::SHARED` are left alone.
+    ///
+    /// # Returns
+    ///
+    /// The page mapped at va afterwards, `MosError::Inval` if va is not mapped,
+    /// `MosError::NoMem` if no page could be allocated.
+    pub fn make_private(self, asid: usize, va: VA) -> Result<Page, MosError> {
+        let (flags, page) = match self.lookup(va) {
+            Some((pte, page)) => (pte.flags(), page),
+            None => return Err(MosError::Inval),
+        };
+        if flags.contains(PteFlags::SHARED)
+            || (page.ref_count() == 1 && !page.is_kernel_image() && !page.is_zero_page())
+        {
+            return Ok(page);
+        }
+        // the copy of the zero page is no longer replaced on the first write
+        let flags = if flags.contains(PteFlags::ZERO) {
+            (flags - PteFlags::ZERO) | PteFlags::D
+        } else {
+            flags
+        };
+        let copy = alloc_user_page(false).ok_or(MosError::NoMem)?;
+        copy_page(page, copy);
+        if let Err(err) = self.insert(asid, copy, va, flags) {
+            page_dealloc(copy);
+            return Err(err);
+        }
+        Ok(copy)
+    }
+
+    /// Map pages at consecutive virtual addresses from va
+    ///
+    /// Large pages are used wherever a whole pair of them fits, that is where va is
+    /// aligned to twice their size and both halves are physically contiguous and
+    /// aligned. Nothing stays mapped on failure.
+    pub fn insert_pages(
+        self,
+        asid: usize,
+        pages: &[Page],
+        va: VA,
+        flags: PteFlags,
+    ) -> Result<(), MosError> {
+        let mut i = 0;
+        while i < pages.len() {
+            let size = PageSize::LARGE
+                .into_iter()
+                .find(|&size| large_pair_fits(&pages[i..], va + i * PAGE_SIZE, size))
+                .unwrap_or(PageSize::Small);
+            let count = match size {
+                PageSize::Small => 1,
+                _ => 2 * size.bytes() / PAGE_SIZE,
+            };
+            for (j, &page) in pages.iter().enumerate().skip(i).take(count) {
+                let flags = flags | size.flags();
+                if let Err(err) = self.insert(asid, page, va + j * PAGE_SIZE, flags) {
+                    (0..j).for_each(|k| self.remove(asid, va + k * PAGE_SIZE));
+                    return Err(err);
+                }
+            }
+            i += count;
+        }
+        Ok(())
+    }
+
+    /// Split the large page pair containing va back into small pages
+    pub fn demote(self, va: VA) {
+        let size = match self.walk(va, false) {
+            Ok(Some(pte)) => pte.page_size(),
+            _ => return,
+        };
+        if size == PageSize::Small {
+            return;
+        }
+        let span = 2 * size.bytes();
+        let base = va.0 & !(span - 1);
+        for addr in (base..base + span).step_by(PAGE_SIZE) {
+            if let Ok(Some(pte)) = self.walk(VA(addr), false) {
+                if pte.page_size() == size {
+                    pte.set_flags(pte.flags() - size.flags());
+                }
+            }
+        }
+        // entries of any address space may cover the pair, e.g. global ones
+        tlb_flush();
+    }
+}
+
+/// Check if pages can start a pair of large pages of size mapped at va
+fn large_pair_fits(pages: &[Page], va: VA, size: PageSize) -> bool {
+    let count = size.bytes() / PAGE_SIZE;
+    va.0 & (2 * size.bytes() - 1) == 0
+        && pages.len() >= 2 * count
+        && pages[..2 * count].chunks_exact(count).all(|half| {
+            half[0].ppn().0 & (count - 1) == 0
+                && half
+                    .iter()
+                    .enumerate()
+                    .all(|(k, page)| page.ppn().0 == half[0].ppn().0 + k)
+        })
 }
 
 /// Page directory defination
@@ -5,19 +5,75 @@
 //! It includes functions for initializing memory, managing the heap and handling page allocation and mapping.
 
 mod addr;
+pub mod cache;
+mod compact;
+pub mod dedup;
 mod heap;
+pub mod highmem;
+pub mod kseg2;
 pub mod layout;
 pub mod map;
 pub mod page;
+#[cfg(feature = "debug_page_alloc")]
+pub mod page_debug;
+pub mod slab;
+pub mod swap;
 mod tlb;
+pub mod vma;
+pub mod vmalloc;
 
 pub use addr::*;
 pub use tlb::tlb_invalidate;
 
-use log::info;
+use core::mem::size_of;
+use log::{info, warn};
+use page::{page_stats, PageRc, ORDER};
+
+/// Memory statistics reported to user space by `sys_mem_stats`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemStats {
+    /// Pages of physical memory
+    pub total_pages: u32,
+    /// Free pages
+    pub free_pages: u32,
+    /// Free blocks of the page allocator per buddy order
+    pub free_blocks: [u32; ORDER],
+    /// Allocated blocks of the page allocator per buddy order
+    pub used_blocks: [u32; ORDER],
+    /// Bytes managed by the kernel heap
+    pub heap_capacity: u32,
+    /// Bytes allocated from the kernel heap
+    pub heap_in_use: u32,
+    /// Swap slots holding a page
+    pub swap_used: u32,
+    /// Resident user pages of the env
+    pub env_rss: u32,
+}
+
+/// Collect global memory statistics and those of the address space of asid
+pub fn mem_stats(asid: usize) -> MemStats {
+    let pages = page_stats();
+    let heap = heap::stats();
+    MemStats {
+        total_pages: pages.total_pages as u32,
+        free_pages: pages.free_pages as u32,
+        free_blocks: pages.free_blocks.map(|count| count as u32),
+        used_blocks: pages.used_blocks.map(|count| count as u32),
+        heap_capacity: heap.capacity as u32,
+        heap_in_use: heap.in_use as u32,
+        swap_used: swap::stats().used_slots as u32,
+        env_rss: map::rss(asid) as u32,
+    }
+}
 
 static mut MEMSIZE: usize = 0;
 static mut PAGENUM: usize = 0;
+static mut LOWMEM_PAGENUM: usize = 0;
+
+/// Most pages whose reference counts fit in the UPAGES window of user space
+const MAX_PAGENUM: usize =
+    layout::PDMAP / layout::PAGE_SIZE * (layout::PAGE_SIZE / size_of::<PageRc>());
 
 /// Initializes the memory management module.
 ///
@@ -36,8 +92,18 @@ pub fn init(memsize: usize) {
         get_memsize() / 1024,
         get_pagenum()
     );
+    if get_pagenum() > get_lowmem_pagenum() {
+        info!(
+            "High memory: {} KiB above the PCI/IO hole, for user pages only.",
+            (get_pagenum() - get_lowmem_pagenum()) * layout::PAGE_SIZE / 1024
+        );
+    }
     heap::init();
     page::init();
+    kseg2::init();
+    highmem::init();
+    vmalloc::self_test();
+    swap::init();
 }
 
 /// Sets the total memory size.
@@ -53,8 +119,18 @@ pub fn init(memsize: usize) {
 /// Panics if the memory size has already been set.
 unsafe fn set_memsize(memsize: usize) {
     assert!(MEMSIZE == 0, "Memory size has been set.");
+    let memsize = if memsize / layout::PAGE_SIZE > MAX_PAGENUM {
+        warn!(
+            "Only the first {} KiB of memory can be tracked, ignoring the rest.",
+            MAX_PAGENUM * layout::PAGE_SIZE / 1024
+        );
+        MAX_PAGENUM * layout::PAGE_SIZE
+    } else {
+        memsize
+    };
     MEMSIZE = memsize;
     PAGENUM = memsize / layout::PAGE_SIZE;
+    LOWMEM_PAGENUM = PAGENUM.min(layout::LOWMEM_SIZE / layout::PAGE_SIZE);
 }
 
 /// Returns the total memory size in bytes.
@@ -76,3 +152,13 @@ pub fn get_memsize() -> usize {
 pub fn get_pagenum() -> usize {
     unsafe { PAGENUM }
 }
+
+/// Returns the number of pages reached through KSEG0.
+///
+/// # Returns
+///
+/// The number of physical pages below the high memory.
+#[inline]
+pub fn get_lowmem_pagenum() -> usize {
+    unsafe { LOWMEM_PAGENUM }
+}
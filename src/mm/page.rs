@@ -1,21 +1,27 @@
 //! Page structure and `PageAllocator` for memory management
 use crate::mutex::{FakeLock, Mutex};
 
+#[cfg(feature = "debug_page_alloc")]
+use super::page_debug;
 use super::{
     addr::{PA, PPN, VA},
-    get_pagenum,
+    compact::compact,
+    get_lowmem_pagenum, get_pagenum,
+    highmem::kmap,
     layout::PAGE_SIZE,
 };
 use alloc::vec::Vec;
 use core::{
     mem::size_of,
     ptr::{addr_of_mut, write_bytes},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use lazy_static::lazy_static;
 use log::trace;
 
 // log_2 (512M / PAGE_SIZE) = 17
-const ORDER: usize = 32;
+/// Number of buddy orders of the page allocator
+pub const ORDER: usize = 32;
 
 /// Page structure for paging memory management
 /// 
@@ -39,6 +45,24 @@ impl Page {
         self.ppn
     }
 
+    /// Check if this page holds the kernel image
+    pub fn is_kernel_image(self) -> bool {
+        extern "C" {
+            static mut __end_kernel: u8;
+        }
+        self.ppn < PPN::from(VA(unsafe { addr_of_mut!(__end_kernel) as usize }).paddr())
+    }
+
+    /// Check if this page is above the KSEG0 window, see `kmap`
+    pub fn is_highmem(self) -> bool {
+        self.ppn.0 >= get_lowmem_pagenum()
+    }
+
+    /// Check if this is the shared zero page
+    pub fn is_zero_page(self) -> bool {
+        self.ppn.0 == ZERO_PPN.load(Ordering::Relaxed)
+    }
+
     /// Acquire page's `ref_count`
     pub fn ref_count(self) -> u16 {
         PAGE_ALLOCATOR.lock().tracker.ref_count(self.ppn).unwrap()
@@ -166,6 +190,23 @@ impl PageRc {
 pub struct PageAllocator {
     tracker: PageTracker,
     free_list: [Vec<PPN>; ORDER],
+    /// Free blocks of high memory, kept apart since the kernel can't reach them directly
+    high_free_list: [Vec<PPN>; ORDER],
+    /// Blocks handed out and not freed yet, per order
+    used: [usize; ORDER],
+}
+
+/// Page allocator usage
+#[derive(Clone, Copy, Debug)]
+pub struct PageStats {
+    /// Pages managed by the allocator, including the kernel image
+    pub total_pages: usize,
+    /// Free pages
+    pub free_pages: usize,
+    /// Free blocks per order
+    pub free_blocks: [usize; ORDER],
+    /// Allocated blocks per order
+    pub used_blocks: [usize; ORDER],
 }
 
 impl PageAllocator {
@@ -176,6 +217,8 @@ impl PageAllocator {
         Self {
             tracker: PageTracker::new(),
             free_list: [NEW_VEC; ORDER],
+            high_free_list: [NEW_VEC; ORDER],
+            used: [0; ORDER],
         }
     }
 
@@ -193,16 +236,15 @@ impl PageAllocator {
     /// PPNS in the range [current, end) are considered free and are added to the free list.
     ///
     /// The free list is organized as an array of vectors, where the ith vector contains all free blocks of size 2^i pages.
+    ///
+    /// PPNs from `get_lowmem_pagenum()` on are high memory and go to a free list of their own.
     fn init(&mut self, start: PPN, end: PPN) {
-        let mut current = start;
-        while current < end {
-            let lowbit = 1 << current.0.trailing_zeros();
-            let size = lowbit.min(prev_power_of_2(end - current));
-            let order = size.trailing_zeros() as usize;
-            self.free_list[order].push(current);
-            current = current + size;
-        }
+        let lowmem_end = end.min(PPN(get_lowmem_pagenum()));
+        add_free_range(&mut self.free_list, start, lowmem_end);
+        add_free_range(&mut self.high_free_list, lowmem_end, end);
         self.init_tracker(start, end);
+        // the tracker itself is not counted as allocated
+        self.used = [0; ORDER];
     }
 
     fn init_tracker(&mut self, start: PPN, end: PPN) {
@@ -239,6 +281,8 @@ impl PageAllocator {
                     (*ptr).inc_ref();
                 }
             }
+            // the pages beyond actual_size go back one by one
+            self.split(alloc_count.trailing_zeros() as usize);
             for i in actual_size..alloc_count {
                 self.dealloc(self.tracker.ppn + i, 1);
             }
@@ -261,26 +305,41 @@ impl PageAllocator {
     fn alloc(&mut self, clear: bool, size: usize) -> Option<PPN> {
         let size = size.next_power_of_two();
         let order = size.trailing_zeros() as usize;
-        for i in order..ORDER {
-            if !self.free_list[i].is_empty() {
-                for j in ((order + 1)..=i).rev() {
-                    if let Some(ppn) = self.free_list[j].pop() {
-                        self.free_list[j - 1].push(ppn + (1 << (j - 1)));
-                        self.free_list[j - 1].push(ppn);
-                    } else {
-                        return None;
-                    }
-                }
-                let ppn = self.free_list[order].pop().expect("There should be a page");
-                if clear {
-                    for j in 0..size {
-                        clear_page(ppn + j);
-                    }
-                }
-                return Some(ppn);
+        let ppn = take_block(&mut self.free_list, order)?;
+        self.hand_out(ppn, order, clear);
+        Some(ppn)
+    }
+
+    /// Allocate a physical page of high memory.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(PPN)` - The physical page number (PPN) of the allocated page.
+    /// * `None` - If there is no free high memory.
+    fn alloc_high(&mut self, clear: bool) -> Option<PPN> {
+        let ppn = take_block(&mut self.high_free_list, 0)?;
+        self.hand_out(ppn, 0, clear);
+        Some(ppn)
+    }
+
+    /// Account a block of 2^order pages at ppn as allocated, clear it if needed
+    fn hand_out(&mut self, ppn: PPN, order: usize, clear: bool) {
+        self.used[order] += 1;
+        #[cfg(feature = "debug_page_alloc")]
+        (0..1 << order).for_each(|j| super::page_debug::check_poison(ppn + j));
+        if clear {
+            for j in 0..1 << order {
+                clear_page(ppn + j);
             }
         }
-        None
+    }
+
+    /// Account an allocated block of 2^order pages as 2^order single pages
+    fn split(&mut self, order: usize) {
+        self.used[order] = self.used[order]
+            .checked_sub(1)
+            .expect("page_split: no block of this order is allocated");
+        self.used[0] += 1 << order;
     }
 
     /// Deallocate a previously allocated block of physical pages.
@@ -292,29 +351,53 @@ impl PageAllocator {
     fn dealloc(&mut self, ppn: PPN, size: usize) {
         assert!(size.is_power_of_two());
         let order = size.trailing_zeros() as usize;
-        self.free_list[order].push(ppn);
+        self.used[order] = self.used[order]
+            .checked_sub(1)
+            .expect("page_dealloc: no block of this order is allocated");
+        let free_list = if Page::new(ppn).is_highmem() {
+            &mut self.high_free_list
+        } else {
+            &mut self.free_list
+        };
+        free_list[order].push(ppn);
         let mut ppn = ppn;
         let mut order = order;
         while order < ORDER - 1 {
             let mut flag = false;
             let buddy = ppn.0 ^ (1 << order);
-            for block in &self.free_list[order] {
+            for block in &free_list[order] {
                 if block.0 == buddy {
                     flag = true;
                     break;
                 }
             }
             if flag {
-                self.free_list[order].retain(|x| x.0 != buddy && *x != ppn);
+                free_list[order].retain(|x| x.0 != buddy && *x != ppn);
                 ppn = PPN(ppn.0 & buddy);
                 order += 1;
-                self.free_list[order].push(ppn);
+                free_list[order].push(ppn);
             } else {
                 break;
             }
         }
     }
 
+    /// Acquire free and used block counts per order
+    pub fn stats(&self) -> PageStats {
+        let mut stats = PageStats {
+            total_pages: get_pagenum(),
+            free_pages: 0,
+            free_blocks: [0; ORDER],
+            used_blocks: self.used,
+        };
+        let lists = self.free_list.iter().zip(self.high_free_list.iter());
+        for (order, (low, high)) in lists.enumerate() {
+            stats.free_blocks[order] = low.len() + high.len();
+            stats.free_pages += (low.len() + high.len()) << order;
+        }
+        stats
+    }
+
     /// Get page tracker's ppn and page count
     ///
     /// # Returns
@@ -325,11 +408,34 @@ impl PageAllocator {
     }
 }
 
+/// Add the pages in [start, end) to free_list, in the largest aligned blocks possible
+fn add_free_range(free_list: &mut [Vec<PPN>; ORDER], start: PPN, end: PPN) {
+    let mut current = start;
+    while current < end {
+        let lowbit = 1 << current.0.trailing_zeros();
+        let size = lowbit.min(prev_power_of_2(end - current));
+        let order = size.trailing_zeros() as usize;
+        free_list[order].push(current);
+        current = current + size;
+    }
+}
+
+/// Take a block of 2^order pages from free_list, splitting a larger block if needed
+fn take_block(free_list: &mut [Vec<PPN>; ORDER], order: usize) -> Option<PPN> {
+    let i = (order..ORDER).find(|&i| !free_list[i].is_empty())?;
+    for j in ((order + 1)..=i).rev() {
+        let ppn = free_list[j].pop()?;
+        free_list[j - 1].push(ppn + (1 << (j - 1)));
+        free_list[j - 1].push(ppn);
+    }
+    free_list[order].pop()
+}
+
 /// Write 0 to ppn's page
 fn clear_page(ppn: PPN) {
-    let va = ppn.kaddr();
+    let map = kmap(Page::new(ppn));
     unsafe {
-        write_bytes(va.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+        write_bytes(map.va().as_mut_ptr::<u8>(), 0, PAGE_SIZE);
     }
 }
 
@@ -338,6 +444,31 @@ lazy_static! {
     pub static ref PAGE_ALLOCATOR: FakeLock<PageAllocator> = FakeLock::new(PageAllocator::new());
 }
 
+/// Set while the page allocator is modifying its free lists.
+///
+/// The free lists live on the kernel heap, so a heap allocation may happen in the
+/// middle of a page allocation. Allocators built on top of pages must not call back
+/// into the page allocator while this is set.
+static ALLOCATOR_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Check if the page allocator is in the middle of an operation
+#[inline]
+pub fn page_allocator_busy() -> bool {
+    ALLOCATOR_BUSY.load(Ordering::Relaxed)
+}
+
+/// Ppn of the shared zero page, set up by `init`
+static ZERO_PPN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Acquire the shared zero page
+///
+/// The page is cleared once and never written, it backs anonymous memory until the first
+/// write. None if its `ref_count` cannot take another mapping.
+pub fn zero_page() -> Option<Page> {
+    let page = Page::new(PPN(ZERO_PPN.load(Ordering::Relaxed)));
+    (page.ref_count() < u16::MAX).then_some(page)
+}
+
 /// Detect used and unused memory limit
 /// Init page allocator
 pub fn init() {
@@ -346,7 +477,15 @@ pub fn init() {
     }
     let start = PPN::from(VA(unsafe { addr_of_mut!(__end_kernel) as usize }).paddr());
     let end = PPN(get_pagenum());
-    PAGE_ALLOCATOR.lock().init(start, end)
+    ALLOCATOR_BUSY.store(true, Ordering::Relaxed);
+    PAGE_ALLOCATOR.lock().init(start, end);
+    ALLOCATOR_BUSY.store(false, Ordering::Relaxed);
+    // the extra reference keeps the zero page from being recycled
+    let zero = page_alloc(true).expect("No memory for the zero page.");
+    page_inc_ref(zero);
+    ZERO_PPN.store(zero.ppn().0, Ordering::Relaxed);
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::init(get_pagenum());
 }
 
 /// You should use `page_alloc` instead
@@ -355,22 +494,76 @@ pub fn init() {
 /// clear page if argument clear is set
 #[inline]
 fn alloc(clear: bool, size: usize) -> Option<PPN> {
-    PAGE_ALLOCATOR.lock().alloc(clear, size)
+    ALLOCATOR_BUSY.store(true, Ordering::Relaxed);
+    let ppn = PAGE_ALLOCATOR.lock().alloc(clear, size);
+    ALLOCATOR_BUSY.store(false, Ordering::Relaxed);
+    ppn
 }
 
 /// Utility function, alloc a page and return it,
 /// return None if there's no free page,
 /// clear page if argument clear is set
-#[inline]
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
 pub fn page_alloc(clear: bool) -> Option<Page> {
-    alloc(clear, 1).map(Page::new)
+    #[cfg(feature = "debug_page_alloc")]
+    let caller = page_debug::return_address!();
+    let ppn = alloc(clear, 1)?;
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_alloc(ppn, 1, caller);
+    Some(Page::new(ppn))
+}
+
+/// Allocate a page for user memory, from high memory if there is any left
+///
+/// A high memory page is only reached by the kernel through `kmap`, it must not hold
+/// anything the kernel uses through KSEG0 like a page table.
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
+pub fn page_alloc_high(clear: bool) -> Option<Page> {
+    #[cfg(feature = "debug_page_alloc")]
+    let caller = page_debug::return_address!();
+    ALLOCATOR_BUSY.store(true, Ordering::Relaxed);
+    let ppn = PAGE_ALLOCATOR.lock().alloc_high(clear);
+    ALLOCATOR_BUSY.store(false, Ordering::Relaxed);
+    let Some(ppn) = ppn else {
+        return page_alloc(clear);
+    };
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_alloc(ppn, 1, caller);
+    Some(Page::new(ppn))
 }
 
 /// Contiguously allocate pages
-#[allow(dead_code)]
-#[inline]
+///
+/// Memory is compacted if no free block is large enough
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
 pub fn page_alloc_contiguous(clear: bool, size: usize) -> Option<Page> {
-    alloc(clear, size).map(Page::new)
+    #[cfg(feature = "debug_page_alloc")]
+    let caller = page_debug::return_address!();
+    let ppn = alloc(clear, size).or_else(|| {
+        // fragmented free lists, try to make room by moving user pages
+        compact(size).then(|| alloc(clear, size)).flatten()
+    })?;
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_alloc(ppn, size, caller);
+    Some(Page::new(ppn))
+}
+
+/// Contiguously allocate pages, without compacting memory
+///
+/// For the kernel heap: it grows at any point, while kernel code may hold pages that
+/// compaction would move, and compaction itself must not need the heap.
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
+pub fn page_alloc_contiguous_no_compact(clear: bool, size: usize) -> Option<Page> {
+    #[cfg(feature = "debug_page_alloc")]
+    let caller = page_debug::return_address!();
+    let ppn = alloc(clear, size)?;
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_alloc(ppn, size, caller);
+    Some(Page::new(ppn))
 }
 
 /// You should use `page_dealloc` instead
@@ -378,23 +571,57 @@ pub fn page_alloc_contiguous(clear: bool, size: usize) -> Option<Page> {
 /// panic if its `ref_count` is not 0
 #[inline]
 fn dealloc(ppn: PPN, size: usize) {
-    PAGE_ALLOCATOR.lock().dealloc(ppn, size)
+    ALLOCATOR_BUSY.store(true, Ordering::Relaxed);
+    PAGE_ALLOCATOR.lock().dealloc(ppn, size);
+    ALLOCATOR_BUSY.store(false, Ordering::Relaxed);
 }
 
 /// Utility function, dealloc a page,
 /// panic if its `ref_count` is not 0
-#[inline]
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
 pub fn page_dealloc(page: Page) {
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_free(page.ppn(), 1, page_debug::return_address!());
     dealloc(page.ppn(), 1);
 }
 
 /// Utility function, dealloc contiguous page of parameter size from page
-#[allow(dead_code)]
-#[inline]
+#[cfg_attr(not(feature = "debug_page_alloc"), inline)]
+#[cfg_attr(feature = "debug_page_alloc", inline(never))]
 pub fn page_dealloc_contiguous(page: Page, size: usize) {
+    #[cfg(feature = "debug_page_alloc")]
+    page_debug::on_free(page.ppn(), size, page_debug::return_address!());
     dealloc(page.ppn(), size);
 }
 
+/// Let the size pages of a block from `page_alloc_contiguous` be freed one by one
+pub fn page_split(size: usize) {
+    assert!(size.is_power_of_two());
+    PAGE_ALLOCATOR.lock().split(size.trailing_zeros() as usize);
+}
+
+/// Fill bitmap with the free pages it covers, by ppn
+pub fn page_free_bitmap(bitmap: &mut [u32]) {
+    bitmap.fill(0);
+    let end = bitmap.len() * 32;
+    let allocator = PAGE_ALLOCATOR.lock();
+    for free_list in [&allocator.free_list, &allocator.high_free_list] {
+        for (order, list) in free_list.iter().enumerate() {
+            for ppn in list {
+                for i in ppn.0..(ppn.0 + (1 << order)).min(end) {
+                    bitmap[i / 32] |= 1 << (i % 32);
+                }
+            }
+        }
+    }
+}
+
+/// Acquire current page allocator usage
+pub fn page_stats() -> PageStats {
+    PAGE_ALLOCATOR.lock().stats()
+}
+
 /// Increase page's `ref_count`
 #[inline]
 pub fn page_inc_ref(page: Page) {
@@ -404,6 +631,14 @@ pub fn page_inc_ref(page: Page) {
 /// Decrease page's `ref_count`
 #[inline]
 pub fn page_dec_ref(page: Page) {
+    #[cfg(feature = "debug_page_alloc")]
+    if page.ref_count() == 0 {
+        panic!(
+            "page_dec_ref: ref_count underflow of {:?}, {}",
+            page,
+            page_debug::history(page.ppn())
+        );
+    }
     PAGE_ALLOCATOR.lock().tracker.dec_ref(page.ppn())
 }
 
@@ -411,6 +646,15 @@ pub fn page_dec_ref(page: Page) {
 /// if page's `ref_count` is set to 0, deallocate the page
 pub fn try_recycle(page: Page) {
     match page.ref_count() {
+        #[cfg(feature = "debug_page_alloc")]
+        0 => {
+            panic!(
+                "try_recycle: page {:?} is not referenced, {}",
+                page,
+                page_debug::history(page.ppn())
+            );
+        }
+        #[cfg(not(feature = "debug_page_alloc"))]
         0 => {
             panic!("try_recycle: page is not referenced.");
         }
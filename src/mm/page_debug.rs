@@ -0,0 +1,159 @@
+//! Debugging aids for the page allocator, enabled by the `debug_page_alloc` feature.
+//!
+//! Freed pages are filled with `POISON` and checked when they are handed out again, so
+//! writes through stale mappings are caught at the next allocation instead of corrupting
+//! whoever gets the page. The last allocation and free of every page are recorded with the
+//! return address of the caller and the current env, and printed when a page is freed
+//! twice, its `ref_count` underflows or its poison was overwritten.
+
+use super::{
+    addr::PPN,
+    highmem::{kmap, KMap},
+    layout::PAGE_SIZE,
+    page::Page,
+};
+use crate::{
+    mutex::{FakeLock, Mutex},
+    pm::ENV_MANAGER,
+};
+use alloc::{vec, vec::Vec};
+use core::{fmt, mem::size_of, slice};
+use lazy_static::lazy_static;
+
+/// Pattern filling freed pages
+const POISON: u32 = 0x6b6b_6b6b;
+
+/// Return address of the function this is expanded in
+///
+/// Only valid before the function makes its first call, and the function must not be
+/// inlined.
+macro_rules! return_address {
+    () => {{
+        let ra: usize;
+        unsafe { core::arch::asm!("move {}, $31", out(reg) ra) };
+        ra
+    }};
+}
+pub(crate) use return_address;
+
+/// Allocation state of a page
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PageState {
+    /// Never allocated through `page_alloc` since boot
+    Untracked,
+    /// Allocated
+    Allocated,
+    /// Freed and poisoned
+    Freed,
+}
+
+/// Last allocation and free of a page
+#[derive(Clone, Copy, Debug)]
+pub struct PageHistory {
+    state: PageState,
+    alloc_caller: usize,
+    alloc_env: usize,
+    free_caller: usize,
+    free_env: usize,
+}
+
+impl PageHistory {
+    const fn new() -> Self {
+        Self {
+            state: PageState::Untracked,
+            alloc_caller: 0,
+            alloc_env: 0,
+            free_caller: 0,
+            free_env: 0,
+        }
+    }
+}
+
+impl fmt::Display for PageHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, allocated by 0x{:08x} (env {:08x}), last freed by 0x{:08x} (env {:08x})",
+            self.state, self.alloc_caller, self.alloc_env, self.free_caller, self.free_env
+        )
+    }
+}
+
+lazy_static! {
+    static ref HISTORY: FakeLock<Vec<PageHistory>> = FakeLock::new(Vec::new());
+}
+
+/// Set up the history of pagenum pages
+pub fn init(pagenum: usize) {
+    *HISTORY.lock() = vec![PageHistory::new(); pagenum];
+}
+
+/// Id of the current env, 0 if there is none
+fn current_env() -> usize {
+    ENV_MANAGER.lock().curenv().map_or(0, |env| env.id)
+}
+
+/// Words of the page mapped by map
+fn page_words(map: &mut KMap) -> &mut [u32] {
+    unsafe { slice::from_raw_parts_mut(map.va().as_mut_ptr::<u32>(), PAGE_SIZE / size_of::<u32>()) }
+}
+
+/// Acquire the history of the page at ppn
+pub fn history(ppn: PPN) -> PageHistory {
+    HISTORY
+        .lock()
+        .get(ppn.0)
+        .copied()
+        .unwrap_or(PageHistory::new())
+}
+
+/// Check that the freed page at ppn was not written before it is handed out again
+pub fn check_poison(ppn: PPN) {
+    if history(ppn).state != PageState::Freed {
+        return;
+    }
+    let mut map = kmap(Page::new(ppn));
+    if let Some(offset) = page_words(&mut map).iter().position(|&word| word != POISON) {
+        panic!(
+            "page_alloc: page {:?} written at offset 0x{:x} after free, {}",
+            ppn,
+            offset * size_of::<u32>(),
+            history(ppn)
+        );
+    }
+}
+
+/// Record the allocation of size pages at ppn by caller
+pub fn on_alloc(ppn: PPN, size: usize, caller: usize) {
+    let env = current_env();
+    let mut history = HISTORY.lock();
+    for record in history.iter_mut().skip(ppn.0).take(size) {
+        record.state = PageState::Allocated;
+        record.alloc_caller = caller;
+        record.alloc_env = env;
+    }
+}
+
+/// Record the free of size pages at ppn by caller and poison them
+///
+/// Panics if any of the pages is already free
+pub fn on_free(ppn: PPN, size: usize, caller: usize) {
+    let env = current_env();
+    for i in 0..size {
+        let page = ppn + i;
+        let Some(mut record) = HISTORY.lock().get(page.0).copied() else {
+            continue;
+        };
+        if record.state == PageState::Freed {
+            panic!(
+                "page_dealloc: double free of page {:?} by 0x{:08x} (env {:08x}), {}",
+                page, caller, env, record
+            );
+        }
+        page_words(&mut kmap(Page::new(page))).fill(POISON);
+        record.state = PageState::Freed;
+        record.free_caller = caller;
+        record.free_env = env;
+        HISTORY.lock()[page.0] = record;
+    }
+}
@@ -0,0 +1,430 @@
+//! Slab allocator for fixed-size kernel objects
+//!
+//! Every slab is a single page taken from the page allocator. The page starts with a
+//! [`Slab`] header, followed by equally sized object slots. Free slots are chained
+//! into a free list stored inside the slots themselves.
+//!
+//! Two kinds of caches are provided:
+//! - [`TypedCache`]: a cache dedicated to one object type, handing out [`SlabBox`]es.
+//! - kmalloc caches: power-of-two size classes used by the global allocator, so small
+//!   heap allocations do not fragment the kernel heap.
+//!
+//! Statistics of every cache can be acquired with [`stats`], user space reads them with
+//! `sys_mem_stats`.
+
+use super::{
+    addr::VA,
+    layout::PAGE_SIZE,
+    page::{page_alloc, page_allocator_busy, page_inc_ref, try_recycle, Page},
+};
+use crate::{
+    mutex::{FakeLock, Mutex},
+    round, round_down,
+};
+use alloc::vec::Vec;
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, null_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use lazy_static::lazy_static;
+
+const SLAB_MAGIC: usize = 0x51ab_c0de;
+
+/// Smallest kmalloc size class
+const KMALLOC_MIN_SIZE: usize = 16;
+/// Largest kmalloc size class, larger allocations go to the heap
+const KMALLOC_MAX_SIZE: usize = 1024;
+/// Number of kmalloc size classes, from 16 to 1024 bytes
+const KMALLOC_CLASSES: usize = 7;
+
+/// Header at the beginning of every slab page
+#[repr(C)]
+struct Slab {
+    magic: usize,
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Free slot, linked into the free list of its slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Bytes of a cache name in [`SlabStats`], longer names are cut
+pub const SLAB_NAME_LEN: usize = 16;
+
+/// Statistics of a slab cache, as reported to user space
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SlabStats {
+    /// Name of the cache, padded with NULs
+    pub name: [u8; SLAB_NAME_LEN],
+    /// Size of each object slot in bytes
+    pub object_size: u32,
+    /// Number of object slots in a slab
+    pub objects_per_slab: u32,
+    /// Number of slabs (pages) owned by the cache
+    pub slabs: u32,
+    /// Number of objects currently allocated
+    pub active_objects: u32,
+    /// Number of successful allocations since boot
+    pub total_allocs: u32,
+    /// Number of frees since boot
+    pub total_frees: u32,
+}
+
+/// Cache of equally sized objects
+///
+/// Slabs are kept in a single list, with every slab that still has a free slot in front
+/// of every full slab, so allocation only needs to look at the head of the list.
+pub struct ObjectCache {
+    name: &'static str,
+    object_size: usize,
+    first_offset: usize,
+    objects_per_slab: usize,
+    head: *mut Slab,
+    tail: *mut Slab,
+    slabs: usize,
+    active_objects: usize,
+    total_allocs: usize,
+    total_frees: usize,
+}
+
+// Slabs are only reached through their cache, which is always behind a lock.
+unsafe impl Send for ObjectCache {}
+
+impl ObjectCache {
+    /// Create an empty cache for objects of `size` bytes aligned to `align`
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < align_of::<FreeObject>() {
+            align_of::<FreeObject>()
+        } else {
+            align
+        };
+        let size = if size < size_of::<FreeObject>() {
+            size_of::<FreeObject>()
+        } else {
+            size
+        };
+        let object_size = round!(size, align);
+        let first_offset = round!(size_of::<Slab>(), align);
+        Self {
+            name,
+            object_size,
+            first_offset,
+            objects_per_slab: (PAGE_SIZE - first_offset) / object_size,
+            head: null_mut(),
+            tail: null_mut(),
+            slabs: 0,
+            active_objects: 0,
+            total_allocs: 0,
+            total_frees: 0,
+        }
+    }
+
+    /// Acquire statistics of this cache
+    pub fn stats(&self) -> SlabStats {
+        let mut name = [0; SLAB_NAME_LEN];
+        let len = self.name.len().min(SLAB_NAME_LEN);
+        name[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        SlabStats {
+            name,
+            object_size: self.object_size as u32,
+            objects_per_slab: self.objects_per_slab as u32,
+            slabs: self.slabs as u32,
+            active_objects: self.active_objects as u32,
+            total_allocs: self.total_allocs as u32,
+            total_frees: self.total_frees as u32,
+        }
+    }
+
+    /// Take a free slot from the first slab, if there is any
+    fn take(&mut self) -> Option<NonNull<u8>> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        unsafe {
+            let object = (*slab).free;
+            if object.is_null() {
+                return None;
+            }
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                // full slabs go to the back of the list
+                self.unlink(slab);
+                self.push_back(slab);
+            }
+            self.active_objects += 1;
+            self.total_allocs += 1;
+            NonNull::new(object.cast())
+        }
+    }
+
+    /// Format a fresh page as a slab and add it to the cache
+    fn add_slab(&mut self, page: Page) {
+        let slab = page.kaddr().as_mut_ptr::<Slab>();
+        unsafe {
+            slab.write(Slab {
+                magic: SLAB_MAGIC,
+                prev: null_mut(),
+                next: null_mut(),
+                free: null_mut(),
+                in_use: 0,
+            });
+            for i in (0..self.objects_per_slab).rev() {
+                let object = slab
+                    .cast::<u8>()
+                    .add(self.first_offset + i * self.object_size)
+                    .cast::<FreeObject>();
+                (*object).next = (*slab).free;
+                (*slab).free = object;
+            }
+        }
+        self.push_front(slab);
+        self.slabs += 1;
+    }
+
+    /// Return a slot to its slab
+    ///
+    /// # Returns
+    ///
+    /// The page of the slab if it became empty and was removed from the cache
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated from this cache.
+    unsafe fn put(&mut self, object: NonNull<u8>, release: bool) -> Option<Page> {
+        let slab = round_down!(object.as_ptr() as usize, PAGE_SIZE) as *mut Slab;
+        assert!(
+            (*slab).magic == SLAB_MAGIC,
+            "ObjectCache::put: {:p} is not a slab object",
+            object
+        );
+        let object = object.as_ptr().cast::<FreeObject>();
+        let was_full = (*slab).free.is_null();
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.active_objects -= 1;
+        self.total_frees += 1;
+        if (*slab).in_use == 0 && release && self.slabs > 1 {
+            // keep the last slab around to avoid thrashing
+            self.unlink(slab);
+            self.slabs -= 1;
+            (*slab).magic = 0;
+            return Some(Page::from(VA(slab as usize).paddr()));
+        }
+        if was_full {
+            self.unlink(slab);
+            self.push_front(slab);
+        }
+        None
+    }
+
+    fn push_front(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.head;
+            if self.head.is_null() {
+                self.tail = slab;
+            } else {
+                (*self.head).prev = slab;
+            }
+        }
+        self.head = slab;
+    }
+
+    fn push_back(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).next = null_mut();
+            (*slab).prev = self.tail;
+            if self.tail.is_null() {
+                self.head = slab;
+            } else {
+                (*self.tail).next = slab;
+            }
+        }
+        self.tail = slab;
+    }
+
+    fn unlink(&mut self, slab: *mut Slab) {
+        unsafe {
+            if (*slab).prev.is_null() {
+                self.head = (*slab).next;
+            } else {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            if (*slab).next.is_null() {
+                self.tail = (*slab).prev;
+            } else {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+        }
+    }
+}
+
+/// Allocate an object slot from `cache`, growing it with a new page if needed.
+///
+/// The cache is never borrowed while the page allocator runs, as the page allocator
+/// itself may allocate from the heap, and thus from the kmalloc caches.
+fn cache_alloc(cache: &FakeLock<ObjectCache>) -> Option<NonNull<u8>> {
+    if let Some(object) = cache.lock().take() {
+        return Some(object);
+    }
+    if page_allocator_busy() {
+        return None;
+    }
+    let page = page_alloc(false)?;
+    page_inc_ref(page);
+    let mut cache = cache.lock();
+    cache.add_slab(page);
+    cache.take()
+}
+
+/// Return an object slot to `cache`, releasing its slab if it became empty.
+///
+/// # Safety
+///
+/// `object` must have been allocated from `cache`.
+unsafe fn cache_free(cache: &FakeLock<ObjectCache>, object: NonNull<u8>) {
+    let release = !page_allocator_busy();
+    let page = cache.lock().put(object, release);
+    if let Some(page) = page {
+        try_recycle(page);
+    }
+}
+
+/// Cache of objects of type `T`
+pub struct TypedCache<T> {
+    cache: FakeLock<ObjectCache>,
+    registered: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+impl<T> TypedCache<T> {
+    /// Create a new empty cache named `name`
+    pub fn new(name: &'static str) -> Self {
+        assert!(
+            size_of::<T>() <= PAGE_SIZE - size_of::<Slab>() && align_of::<T>() <= PAGE_SIZE / 2,
+            "TypedCache::new: {} is too large for a slab",
+            name
+        );
+        Self {
+            cache: FakeLock::new(ObjectCache::new(name, size_of::<T>(), align_of::<T>())),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `value` into an object allocated from this cache
+    ///
+    /// # Returns
+    ///
+    /// `Some(SlabBox)` on success, `None` if no page is available
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            SLAB_REGISTRY.lock().push(&self.cache);
+        }
+        let ptr = cache_alloc(&self.cache)?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+}
+
+/// Owning pointer to an object allocated from a [`TypedCache`]
+///
+/// Works like `Box<T>`, the object is dropped and its slot returned to the cache when
+/// the `SlabBox` is dropped.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static TypedCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            cache_free(&self.cache.cache, self.ptr.cast());
+        }
+    }
+}
+
+lazy_static! {
+    /// kmalloc caches, one per power-of-two size class
+    static ref KMALLOC_CACHES: [FakeLock<ObjectCache>; KMALLOC_CLASSES] = [
+        FakeLock::new(ObjectCache::new("kmalloc-16", 16, 16)),
+        FakeLock::new(ObjectCache::new("kmalloc-32", 32, 32)),
+        FakeLock::new(ObjectCache::new("kmalloc-64", 64, 64)),
+        FakeLock::new(ObjectCache::new("kmalloc-128", 128, 128)),
+        FakeLock::new(ObjectCache::new("kmalloc-256", 256, 256)),
+        FakeLock::new(ObjectCache::new("kmalloc-512", 512, 512)),
+        FakeLock::new(ObjectCache::new("kmalloc-1024", 1024, 1024)),
+    ];
+
+    /// Typed caches that have been used at least once
+    static ref SLAB_REGISTRY: FakeLock<Vec<&'static FakeLock<ObjectCache>>> =
+        FakeLock::new(Vec::new());
+}
+
+/// Get the kmalloc size class index for `layout`, `None` if it is not served by slabs
+fn kmalloc_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(KMALLOC_MIN_SIZE);
+    if size > KMALLOC_MAX_SIZE {
+        return None;
+    }
+    Some((size.next_power_of_two() / KMALLOC_MIN_SIZE).trailing_zeros() as usize)
+}
+
+/// Allocate memory for `layout` from the kmalloc caches
+///
+/// # Returns
+///
+/// `None` if `layout` is too large for the kmalloc caches or no page is available,
+/// the caller should fall back to the heap in this case.
+pub fn kmalloc(layout: Layout) -> Option<NonNull<u8>> {
+    cache_alloc(&KMALLOC_CACHES[kmalloc_class(layout)?])
+}
+
+/// Free memory allocated by [`kmalloc`]
+///
+/// # Safety
+///
+/// `ptr` must have been returned by [`kmalloc`] with the same `layout`.
+pub unsafe fn kfree(ptr: NonNull<u8>, layout: Layout) {
+    let class = kmalloc_class(layout).expect("kfree: layout is not served by kmalloc");
+    cache_free(&KMALLOC_CACHES[class], ptr);
+}
+
+/// Acquire statistics of every kmalloc cache and every typed cache in use
+pub fn stats() -> Vec<SlabStats> {
+    let registry = SLAB_REGISTRY.lock().clone();
+    KMALLOC_CACHES
+        .iter()
+        .chain(registry)
+        .map(|cache| cache.lock().stats())
+        .collect()
+}
@@ -0,0 +1,347 @@
+//! Swap space for user pages.
+//!
+//! When the page allocator runs out of pages, a victim user page is picked with the
+//! clock algorithm and written to the swap disk. Its page table entry keeps the original
+//! flags with `PteFlags::V` cleared and the swap slot in place of the ppn, see
+//! `Pte::swapped`, so `do_tlb_refill` brings the page back on the next access.
+//!
+//! Only private pages are swapped out: pages whose `ref_count` is above 1 (mapped by
+//! several envs, or held by the kernel like mempool pages and the zero page) and pages
+//! marked `COW` or `SHARED` are skipped. The clock hand sweeps over the user mappings
+//! of all envs, and pages refilled into the TLB since the hand last passed get a
+//! second chance.
+//!
+//! # Note
+//!
+//! Swap lives on IDE disk 1, disk 0 belongs to the file system server, whose access to
+//! the controller goes through the kernel, see `ide`. User code inspecting `vpt` sees a
+//! swapped page as unmapped, which is why `sys_exofork` swaps the parent back in, and
+//! no page of it is swapped out again until the child is started.
+
+use super::{
+    highmem::kmap,
+    layout::{PteFlags, PAGE_SIZE, PDMAP, UTEMP, UTOP},
+    map::{rss_dec, rss_inc, PageDirectory, Pte},
+    page::{page_alloc, page_alloc_high, page_inc_ref, try_recycle, zero_page, Page},
+    tlb::tlb_invalidate,
+    get_pagenum, VA,
+};
+use crate::{
+    error::MosError,
+    mutex::{FakeLock, Mutex},
+    platform::ide::{ide_probe, ide_read, ide_write, SECT_SIZE},
+    pm::{Env, EnvStatus, ENV_MANAGER, NENV},
+};
+use alloc::{vec, vec::Vec};
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+/// IDE disk holding the swap area
+const SWAP_DISK: u8 = 1;
+/// First sector of the swap area
+const SWAP_START_SECTOR: u32 = 0;
+/// Number of page sized slots in the swap area, 32 MiB
+const SWAP_SLOTS: usize = 8192;
+/// Sectors per swap slot
+const SECTS_PER_PAGE: usize = PAGE_SIZE / SECT_SIZE;
+
+/// Swap usage
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct SwapStats {
+    /// Slots in the swap area, 0 if swap is disabled
+    pub total_slots: usize,
+    /// Slots holding a page
+    pub used_slots: usize,
+    /// Pages written to swap since boot
+    pub swapped_out: usize,
+    /// Pages read back from swap since boot
+    pub swapped_in: usize,
+}
+
+/// Swap slot allocator and clock state
+struct SwapManager {
+    enabled: bool,
+    /// Bitmap of used slots
+    slots: Vec<u32>,
+    used_slots: usize,
+    /// Bitmap of pages refilled into the TLB since the clock hand passed them, by ppn
+    referenced: Vec<u32>,
+    /// Env position the clock hand points to
+    hand_env: usize,
+    /// Virtual address the clock hand points to
+    hand_va: usize,
+    swapped_out: usize,
+    swapped_in: usize,
+}
+
+impl SwapManager {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            slots: Vec::new(),
+            used_slots: 0,
+            referenced: Vec::new(),
+            hand_env: 0,
+            hand_va: UTEMP,
+            swapped_out: 0,
+            swapped_in: 0,
+        }
+    }
+
+    fn slot_alloc(&mut self) -> Option<usize> {
+        let (i, word) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != u32::MAX)?;
+        let bit = word.trailing_ones() as usize;
+        *word |= 1 << bit;
+        self.used_slots += 1;
+        Some(i * 32 + bit)
+    }
+
+    fn slot_free(&mut self, slot: usize) {
+        let word = &mut self.slots[slot / 32];
+        assert!(*word & (1 << (slot % 32)) != 0, "swap: slot {} is free", slot);
+        *word &= !(1 << (slot % 32));
+        self.used_slots -= 1;
+    }
+
+    /// Check and clear the referenced bit of page
+    fn test_and_clear_referenced(&mut self, page: Page) -> bool {
+        let (i, bit) = (page.ppn().0 / 32, page.ppn().0 % 32);
+        match self.referenced.get_mut(i) {
+            Some(word) if *word & (1 << bit) != 0 => {
+                *word &= !(1 << bit);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+lazy_static! {
+    static ref SWAP_MANAGER: FakeLock<SwapManager> = FakeLock::new(SwapManager::new());
+}
+
+/// Set up swap if the swap disk is present
+pub fn init() {
+    if !ide_probe(SWAP_DISK) {
+        warn!("Swap disk not found, swapping disabled.");
+        return;
+    }
+    let mut manager = SWAP_MANAGER.lock();
+    manager.slots = vec![0; SWAP_SLOTS / 32];
+    manager.referenced = vec![0; get_pagenum().div_ceil(32)];
+    manager.enabled = true;
+    info!(
+        "Swap enabled on disk {}, {} KiB.",
+        SWAP_DISK,
+        SWAP_SLOTS * PAGE_SIZE / 1024
+    );
+}
+
+/// Sector of slot in the swap area
+const fn slot_sector(slot: usize) -> u32 {
+    SWAP_START_SECTOR + (slot * SECTS_PER_PAGE) as u32
+}
+
+/// Record that page has been accessed, called on TLB refill
+pub fn mark_referenced(page: Page) {
+    let (i, bit) = (page.ppn().0 / 32, page.ppn().0 % 32);
+    if let Some(word) = SWAP_MANAGER.lock().referenced.get_mut(i) {
+        *word |= 1 << bit;
+    }
+}
+
+/// Release the swap slot of a swapped-out pte
+pub fn free_slot(pte: Pte) {
+    SWAP_MANAGER.lock().slot_free(pte.swap_slot());
+}
+
+/// Check if the mapping at pte may be swapped out
+fn swappable(pte: &Pte) -> bool {
+    pte.is_valid()
+        && !pte.flags().intersects(PteFlags::COW | PteFlags::SHARED)
+        && Page::new(pte.ppn()).ref_count() == 1
+}
+
+/// Check if env is between `sys_exofork` and the start of the child, see `EnvExt::forking`
+fn forking(env: &Env) -> bool {
+    env.ext().forking.is_some_and(|child| {
+        ENV_MANAGER
+            .lock()
+            .env_from_id(child, false)
+            .is_ok_and(|child| child.status == EnvStatus::NotRunnable)
+    })
+}
+
+/// Advance the clock hand to the next victim
+///
+/// # Returns
+///
+/// The page directory, asid and virtual address of the victim, or None if no page
+/// could be found in two sweeps.
+fn select_victim() -> Option<(PageDirectory, usize, VA)> {
+    for _ in 0..=2 * NENV {
+        let (pos, start) = {
+            let manager = SWAP_MANAGER.lock();
+            (manager.hand_env, manager.hand_va)
+        };
+        if let Some(env) = ENV_MANAGER
+            .lock()
+            .env_in_use(pos)
+            .filter(|env| !forking(env))
+        {
+            let pgdir = env.pgdir();
+            let mut va = start;
+            while va < UTOP {
+                let pte = match pgdir.walk(VA(va), false) {
+                    Ok(Some(pte)) => pte,
+                    _ => {
+                        // skip the whole page table
+                        va = (va & !(PDMAP - 1)) + PDMAP;
+                        continue;
+                    }
+                };
+                let mut manager = SWAP_MANAGER.lock();
+                if swappable(pte) && !manager.test_and_clear_referenced(Page::new(pte.ppn())) {
+                    manager.hand_va = va + PAGE_SIZE;
+                    return Some((pgdir, env.asid, VA(va)));
+                }
+                va += PAGE_SIZE;
+            }
+        }
+        let mut manager = SWAP_MANAGER.lock();
+        manager.hand_env = (pos + 1) % NENV;
+        manager.hand_va = UTEMP;
+    }
+    None
+}
+
+/// Write one victim page to swap and release it
+///
+/// # Returns
+///
+/// `true` if a page was freed
+fn swap_out_one() -> bool {
+    if !SWAP_MANAGER.lock().enabled {
+        return false;
+    }
+    let Some((pgdir, asid, va)) = select_victim() else {
+        return false;
+    };
+    let Some(slot) = SWAP_MANAGER.lock().slot_alloc() else {
+        warn!("Swap space exhausted.");
+        return false;
+    };
+    let Some((pte, page)) = pgdir.lookup(va) else {
+        unreachable!()
+    };
+    if let Err(err) = ide_write(SWAP_DISK, slot_sector(slot), kmap(page).bytes()) {
+        warn!("Swap out of page {:?} failed: {:?}", page, err);
+        SWAP_MANAGER.lock().slot_free(slot);
+        return false;
+    }
+    *pte = Pte::swapped(slot, pte.flags());
+    tlb_invalidate(asid, va);
+    try_recycle(page);
+    rss_dec(asid, va);
+    SWAP_MANAGER.lock().swapped_out += 1;
+    true
+}
+
+/// Call alloc until it succeeds, swapping out a user page after each failure
+fn alloc_or_swap(alloc: impl Fn() -> Option<Page>) -> Option<Page> {
+    loop {
+        if let Some(page) = alloc() {
+            return Some(page);
+        }
+        if !swap_out_one() {
+            return None;
+        }
+    }
+}
+
+/// Allocate a page for user space, swapping out another user page if memory is short
+///
+/// The page may be in high memory, see `kmap`.
+/// Clear page if argument clear is set
+pub fn alloc_user_page(clear: bool) -> Option<Page> {
+    alloc_or_swap(|| page_alloc_high(clear))
+}
+
+/// Allocate a cleared page table for user space, swapping out a user page if memory is
+/// short
+///
+/// Unlike `alloc_user_page`, the page is always reached through KSEG0.
+pub fn alloc_user_page_table() -> Option<Page> {
+    alloc_or_swap(|| page_alloc(true))
+}
+
+/// Allocate a page for fresh anonymous memory
+///
+/// Private memory is backed by the shared zero page until its first write, shared
+/// memory always gets a cleared page of its own.
+pub fn alloc_anon_page(shared: bool) -> Option<Page> {
+    match zero_page() {
+        Some(page) if !shared => Some(page),
+        _ => alloc_user_page(true),
+    }
+}
+
+/// Bring the page at va back from swap if it has been swapped out
+///
+/// # Returns
+///
+/// `Ok(true)` if the page was swapped in, `Ok(false)` if it was not swapped out,
+/// `MosError::NoMem` if no page could be allocated for it.
+pub fn swap_in(pgdir: PageDirectory, asid: usize, va: VA) -> Result<bool, MosError> {
+    match pgdir.walk(va, false) {
+        Ok(Some(pte)) if pte.is_swapped() => {}
+        _ => return Ok(false),
+    }
+    // the victim search never picks a swapped pte, so the entry stays as it is
+    let page = alloc_user_page(false).ok_or(MosError::NoMem)?;
+    let Ok(Some(pte)) = pgdir.walk(va, false) else {
+        unreachable!()
+    };
+    let slot = pte.swap_slot();
+    if let Err(err) = ide_read(SWAP_DISK, slot_sector(slot), kmap(page).bytes_mut()) {
+        panic!("swap: failed to read slot {}: {:?}", slot, err);
+    }
+    SWAP_MANAGER.lock().slot_free(slot);
+    *pte = Pte::new(page.ppn(), pte.flags() | PteFlags::V);
+    page_inc_ref(page);
+    tlb_invalidate(asid, va);
+    rss_inc(asid, va);
+    SWAP_MANAGER.lock().swapped_in += 1;
+    Ok(true)
+}
+
+/// Bring every swapped-out page of an address space back into memory
+pub fn swap_in_all(pgdir: PageDirectory, asid: usize) -> Result<(), MosError> {
+    let mut va = UTEMP;
+    while va < UTOP {
+        match pgdir.walk(VA(va), false) {
+            Ok(Some(_)) => {
+                swap_in(pgdir, asid, VA(va))?;
+                va += PAGE_SIZE;
+            }
+            _ => va = (va & !(PDMAP - 1)) + PDMAP,
+        }
+    }
+    Ok(())
+}
+
+/// Acquire current swap usage
+pub fn stats() -> SwapStats {
+    let manager = SWAP_MANAGER.lock();
+    SwapStats {
+        total_slots: if manager.enabled { SWAP_SLOTS } else { 0 },
+        used_slots: manager.used_slots,
+        swapped_out: manager.swapped_out,
+        swapped_in: manager.swapped_in,
+    }
+}
@@ -2,21 +2,28 @@
 
 use super::{
     addr::{VA, VPN},
-    layout::{PteFlags, PAGE_SIZE, UENVS, ULIM, UPAGES, USTACKTOP, UTEMP, UVPT, UXSTACKTOP},
-    map::{PageDirectory, Pte},
-    page::page_alloc,
+    kseg2,
+    layout::{
+        PteFlags, KSEG2, KSTACKTOP, PAGE_SIZE, UENVS, ULIM, UPAGES, USTACKTOP, UTEMP, UVPT,
+        UXSTACKTOP,
+    },
+    map::{PageDirectory, PageSize, Pte},
+    swap::{alloc_anon_page, alloc_user_page_table, mark_referenced},
 };
 use crate::{
-    exception::{Trapframe, TF_SIZE},
+    exception::{enter_user_handler, Trapframe, TF_SIZE},
     mutex::Mutex,
-    pm::ENV_MANAGER,
+    platform::cp0reg::STATUS_UM,
+    pm::{debug, env_destroy, schedule, Env, StackGrowth, ENV_MANAGER},
 };
-use core::{arch::global_asm, mem::size_of};
+use core::arch::global_asm;
+use log::warn;
 
 global_asm!(include_str!("../../asm/mm/tlb.S"));
 
 extern "C" {
     fn _tlb_out(entryhi: u32);
+    fn _tlb_flush();
 }
 
 /// Same function with tlb_invalidate in mos
@@ -28,9 +35,50 @@ pub fn tlb_invalidate(asid: usize, va: VA) {
     }
 }
 
+/// Invalidate every TLB entry, of all address spaces
+pub fn tlb_flush() {
+    unsafe {
+        _tlb_flush();
+    }
+}
+
+/// EntryLo pair and PageMask for a TLB entry mapping va as a large page
+///
+/// # Returns
+///
+/// None if pte, the entry of va, is not part of a complete large page pair
+fn large_entry(pgdir: PageDirectory, va: VA, pte: Pte) -> Option<[u32; 3]> {
+    let size = pte.page_size();
+    if size == PageSize::Small {
+        return None;
+    }
+    let base = va.0 & !(2 * size.bytes() - 1);
+    let even = large_half(pgdir, VA(base), size)?;
+    let odd = large_half(pgdir, VA(base + size.bytes()), size)?;
+    Some([even.as_entrylo(), odd.as_entrylo(), size.page_mask()])
+}
+
+/// First pte of the large page of size at va
+///
+/// The TLB maps the whole page from it, so every pte of the page must be valid, have its
+/// flags, and map the next of physically contiguous pages starting at a size aligned ppn.
+fn large_half(pgdir: PageDirectory, va: VA, size: PageSize) -> Option<Pte> {
+    let count = size.bytes() / PAGE_SIZE;
+    let first = *pgdir.walk(va, false).ok()??;
+    if !first.is_valid() || first.page_size() != size || first.ppn().0 & (count - 1) != 0 {
+        return None;
+    }
+    (1..count)
+        .all(|i| {
+            let expected = Pte::new(first.ppn() + i, first.flags());
+            matches!(pgdir.walk(va + i * PAGE_SIZE, false), Ok(Some(pte)) if pte.0 == expected.0)
+        })
+        .then_some(first)
+}
+
 /// Same function with passive_alloc in mos
-/// alloc a page at va, insert it into pgdir
-pub fn passive_alloc(va: VA, pgdir: PageDirectory, asid: usize) {
+/// alloc a page at va, insert it into pgdir with flags
+pub fn passive_alloc(va: VA, pgdir: PageDirectory, asid: usize, flags: PteFlags) {
     let va_val = va.0;
     assert!(va_val >= UTEMP, "Passive alloc: address too low.");
     assert!(
@@ -47,53 +95,154 @@ pub fn passive_alloc(va: VA, pgdir: PageDirectory, asid: usize) {
     );
     assert!(va_val < ULIM, "Passive alloc: kernel address");
 
-    let page = page_alloc(true).unwrap();
-    let flags = if (UVPT..ULIM).contains(&va_val) {
-        PteFlags::empty()
+    // page tables must not be backed by the zero page or by high memory
+    let page = if (UVPT..ULIM).contains(&va_val) {
+        alloc_user_page_table()
     } else {
-        PteFlags::D
+        alloc_anon_page(flags.contains(PteFlags::SHARED))
     };
+    let page = page.expect("Passive alloc: out of memory.");
     pgdir.insert(asid, page, va.pte_addr(), flags).unwrap();
 }
 
 /// Same function with do_tlb_refill in mos
 /// Refill TLB
+///
+/// Misses in KSEG2 can only come from the kernel and are served from the mappings of
+/// `kseg2`, all others from the page directory of the current env.
+///
+/// pentrylo receives EntryLo0, EntryLo1 and PageMask of the new entry
 #[no_mangle]
 pub unsafe extern "C" fn do_tlb_refill(pentrylo: *mut u32, va: u32, asid: u32) {
     let va = VA(va as usize);
     let asid = asid as usize;
     tlb_invalidate(asid, va);
 
-    let pte_base: *mut Pte;
+    let entry = if va.0 >= KSEG2 {
+        kseg2::tlb_entry(va)
+            .unwrap_or_else(|| panic!("do_tlb_refill: kernel TLB miss at {:#010x}", va.0))
+    } else {
+        user_tlb_entry(va, asid)
+    };
+    for (i, word) in entry.into_iter().enumerate() {
+        pentrylo.add(i).write_volatile(word);
+    }
+}
+
+/// EntryLo pair and PageMask for a TLB entry mapping va of the current env
+///
+/// Pages are brought in, allocated or the stack grown as needed. If va is outside of
+/// the virtual memory areas of the env, the fault is delivered to it and the entry
+/// returned is invalid.
+unsafe fn user_tlb_entry(va: VA, asid: usize) -> [u32; 3] {
     loop {
-        if let Some((pte, _)) = ENV_MANAGER.lock().cur_pgdir().lookup(va) {
-            pte_base = ((pte as *mut Pte as usize) & !0x7) as *mut Pte;
-            break;
+        let pgdir = *ENV_MANAGER.lock().cur_pgdir();
+        if let Some((pte, page)) = pgdir.lookup(va) {
+            mark_referenced(page);
+            return large_entry(pgdir, va, *pte).unwrap_or_else(|| {
+                let pte_base = ((pte as *mut Pte as usize) & !0x7) as *mut Pte;
+                [
+                    (*pte_base).as_entrylo(),
+                    (*pte_base.add(1)).as_entrylo(),
+                    PageSize::Small.page_mask(),
+                ]
+            });
         }
-        passive_alloc(va, *ENV_MANAGER.lock().cur_pgdir(), asid);
+        let Some(env) = ENV_MANAGER.lock().curenv() else {
+            panic!("do_tlb_refill: TLB miss at {:#010x} without env", va.0);
+        };
+        match env.page_in(va) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(_) => panic!("do_tlb_refill: out of memory paging in {:#010x}", va.0),
+        }
+        if (UVPT..ULIM).contains(&va.0) {
+            // a page table of the env which doesn't exist yet, map an empty one
+            passive_alloc(va, env.pgdir(), asid, PteFlags::empty());
+            continue;
+        }
+        match env.ext().vmas.find(va.0) {
+            Some(vma) if vma.kind.demand_paged() => {
+                passive_alloc(va, env.pgdir(), asid, vma.perm);
+            }
+            _ => {
+                let sp = unsafe { (*Trapframe::from_memory(VA(KSTACKTOP - TF_SIZE))).regs[29] };
+                let reason = match env.ext().grow_stack(va.0, sp as usize) {
+                    StackGrowth::Grown => continue,
+                    StackGrowth::Overflow => "stack overflow",
+                    StackGrowth::NotStack => "segmentation fault",
+                };
+                deliver_fault(env, va, reason);
+                // the env resumes in its handler, a later access to va misses again
+                return [0, 0, PageSize::Small.page_mask()];
+            }
+        }
+    }
+}
+
+/// Deliver a fault of the current env at va to its exception handler
+///
+/// The env is killed instead if it registered no handler with `sys_set_exception_entry`,
+/// if the fault is the kernel's in a syscall, or if it hit the exception stack, where
+/// the handler would fault again. A traced env is stopped first.
+unsafe fn deliver_fault(env: &mut Env, va: VA, reason: &str) {
+    let tf = Trapframe::from_memory(VA(KSTACKTOP - TF_SIZE));
+    let entry = env.ext().user_exception_entry;
+    // Mod, TLBL or TLBS, the frame of the env is that of the fault itself
+    if (1..=3).contains(&((*tf).cp0_cause >> 2 & 0x1f))
+        && entry != 0
+        && !(USTACKTOP..UXSTACKTOP).contains(&va.0)
+    {
+        debug::stop_on_exception(env, &*tf);
+        enter_user_handler(tf, entry);
+        return;
     }
-    pentrylo.write_volatile((*pte_base).as_entrylo());
-    pentrylo
-        .add(1)
-        .write_volatile((*pte_base.add(1)).as_entrylo());
+    kill_on_fault(env, va, reason)
+}
+
+/// Kill the current env after it touched va outside of its virtual memory areas
+///
+/// A traced env is stopped instead, unless the fault is the kernel's in a syscall.
+fn kill_on_fault(env: &mut Env, va: VA, reason: &str) -> ! {
+    let tf = unsafe { &*Trapframe::from_memory(VA(KSTACKTOP - TF_SIZE)) };
+    // Mod, TLBL or TLBS, the frame of the env is that of the fault itself
+    if (1..=3).contains(&(tf.cp0_cause >> 2 & 0x1f)) {
+        debug::stop_on_exception(env, tf);
+    }
+    warn!(
+        "{:08x}: {} at 0x{:08x} for 0x{:08x}, killing...",
+        env.id, reason, tf.cp0_epc, va.0
+    );
+    env_destroy(env);
+    schedule(true)
 }
 
 /// Same function with do_tlb_mod in mos
 /// This is the kernel TLB Mod exception handler
+///
+/// Writes to the zero page are served here. Copy-on-write faults go to the user
+/// handler if the env registered one, and are resolved by the kernel otherwise. Writes
+/// to read-only pages are delivered as faults.
 #[no_mangle]
 pub unsafe extern "C" fn do_tlb_mod(tf: *mut Trapframe) {
-    let tmp_tf = *tf;
-
-    if !(USTACKTOP..UXSTACKTOP).contains(&((*tf).regs[29] as usize)) {
-        (*tf).regs[29] = UXSTACKTOP as u32;
+    let va = VA((*tf).cp0_badvaddr as usize);
+    let Some(env) = ENV_MANAGER.lock().curenv() else {
+        panic!("do_tlb_mod: TLB Mod at {:#010x} without env", va.0);
+    };
+    match env.pgdir().unshare_zero(env.asid, va) {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(_) => kill_on_fault(env, va, "out of memory"),
     }
-    (*tf).regs[29] -= TF_SIZE as u32;
-    *((*tf).regs[29] as *mut Trapframe) = tmp_tf;
-    if ENV_MANAGER.lock().curenv().unwrap().user_tlb_mod_entry != 0 {
-        (*tf).regs[4] = (*tf).regs[29];
-        (*tf).regs[29] -= size_of::<u32>() as u32;
-        (*tf).cp0_epc = ENV_MANAGER.lock().curenv().unwrap().user_tlb_mod_entry as u32;
-    } else {
-        panic!("do_tlb_mod: TLB Mod but no user handler registered");
+    // faults taken in the kernel, e.g. on syscall buffers, never go to user space
+    let from_kernel = (*tf).cp0_status as usize & STATUS_UM == 0;
+    if env.user_tlb_mod_entry == 0 || from_kernel {
+        match env.pgdir().resolve_cow(env.asid, va) {
+            Ok(true) => return,
+            Ok(false) => return deliver_fault(env, va, "write to read-only page"),
+            Err(_) => kill_on_fault(env, va, "out of memory"),
+        }
     }
+
+    enter_user_handler(tf, env.user_tlb_mod_entry);
 }
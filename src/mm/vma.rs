@@ -0,0 +1,264 @@
+//! Virtual memory areas of user address spaces.
+//!
+//! Every env owns a `VmaTree` describing which parts of its address space may be
+//! touched. A TLB miss on an address without a page is only served with a fresh zero page
+//! if the address lies in a demand-paged area, any other miss is a fault of the env.
+
+use super::{
+    highmem::copy_page,
+    layout::{PteFlags, PAGE_SIZE},
+    map::PageDirectory,
+    page::{page_dealloc, Page},
+    swap::alloc_user_page,
+    tlb::tlb_invalidate,
+    VA,
+};
+use crate::error::MosError;
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// Kind of a virtual memory area
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmaKind {
+    /// User stack below USTACKTOP
+    Stack,
+    /// Segment of the ELF image
+    Elf,
+    /// Pages mapped through `sys_mem_alloc`, `sys_mem_map` or IPC
+    Heap,
+    /// Memory pool joined by the env, mapped only while a lock is held
+    Mempool,
+    /// Mapping created by `sys_mmap`
+    Anonymous,
+}
+
+impl VmaKind {
+    /// Check if a TLB miss in this kind of area is served with a zero page
+    pub const fn demand_paged(self) -> bool {
+        !matches!(self, Self::Mempool)
+    }
+}
+
+/// A virtual memory area, [start, end) with page aligned bounds
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    /// Start address
+    pub start: usize,
+    /// End address, exclusive
+    pub end: usize,
+    /// Permission of pages allocated in this area
+    pub perm: PteFlags,
+    /// Kind of this area
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    /// Construct a new area
+    pub const fn new(start: usize, end: usize, perm: PteFlags, kind: VmaKind) -> Self {
+        Self {
+            start,
+            end,
+            perm,
+            kind,
+        }
+    }
+
+    /// Check if va lies in this area
+    pub const fn contains(&self, va: usize) -> bool {
+        va >= self.start && va < self.end
+    }
+}
+
+/// Non-overlapping areas of an address space, by start address
+#[derive(Clone, Debug)]
+pub struct VmaTree {
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl VmaTree {
+    /// Create an empty tree
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Find the area containing va
+    pub fn find(&self, va: usize) -> Option<&Vma> {
+        self.areas
+            .range(..=va)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(va))
+    }
+
+    /// Check if any area intersects [start, end)
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /// Iterate over all areas in address order
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Add an area, replacing whatever was mapped in its range before
+    pub fn map(&mut self, vma: Vma) {
+        self.unmap(vma.start, vma.end);
+        self.areas.insert(vma.start, vma);
+        self.merge(vma.start);
+    }
+
+    /// Add an area of kind for the parts of [start, end) not covered yet
+    pub fn cover(&mut self, start: usize, end: usize, perm: PteFlags, kind: VmaKind) {
+        let mut cur = start;
+        while cur < end {
+            if let Some(vma) = self.find(cur) {
+                cur = vma.end;
+                continue;
+            }
+            let next = self
+                .areas
+                .range(cur..end)
+                .next()
+                .map_or(end, |(&start, _)| start);
+            self.map(Vma::new(cur, next, perm, kind));
+            cur = next;
+        }
+    }
+
+    /// Remove [start, end) from the tree, splitting areas crossing its bounds
+    pub fn unmap(&mut self, start: usize, end: usize) {
+        let overlapped: Vec<usize> = self
+            .areas
+            .range(..end)
+            .rev()
+            .take_while(|(_, vma)| vma.end > start)
+            .map(|(&start, _)| start)
+            .collect();
+        for key in overlapped {
+            let vma = self.areas.remove(&key).unwrap();
+            if vma.start < start {
+                self.areas
+                    .insert(vma.start, Vma::new(vma.start, start, vma.perm, vma.kind));
+            }
+            if vma.end > end {
+                self.areas
+                    .insert(end, Vma::new(end, vma.end, vma.perm, vma.kind));
+            }
+        }
+    }
+
+    /// Change the permission of [start, end)
+    ///
+    /// # Returns
+    ///
+    /// `MosError::Inval` if the range is not entirely covered by areas
+    pub fn protect(&mut self, start: usize, end: usize, perm: PteFlags) -> Result<(), MosError> {
+        let mut cur = start;
+        let mut pieces = Vec::new();
+        while cur < end {
+            let vma = self.find(cur).ok_or(MosError::Inval)?;
+            pieces.push(Vma::new(cur, vma.end.min(end), perm, vma.kind));
+            cur = vma.end;
+        }
+        self.unmap(start, end);
+        for vma in pieces {
+            self.map(vma);
+        }
+        Ok(())
+    }
+
+    /// Find a free range of len bytes in [low, high), as high as possible
+    pub fn find_free(&self, len: usize, low: usize, high: usize) -> Option<usize> {
+        let mut top = high;
+        for (_, vma) in self.areas.range(..high).rev() {
+            let bottom = vma.end.max(low);
+            if top >= bottom + len {
+                return Some(top - len);
+            }
+            top = top.min(vma.start);
+            if top < low + len {
+                return None;
+            }
+        }
+        (top >= low + len).then(|| top - len)
+    }
+
+    /// Merge the area starting at start with its neighbours of the same kind and permission
+    fn merge(&mut self, start: usize) {
+        let Some(&vma) = self.areas.get(&start) else {
+            return;
+        };
+        let mut merged = vma;
+        if let Some((_, prev)) = self.areas.range(..start).next_back() {
+            if prev.end == start && prev.kind == vma.kind && prev.perm == vma.perm {
+                merged.start = prev.start;
+            }
+        }
+        if let Some(next) = self.areas.get(&vma.end) {
+            if next.kind == vma.kind && next.perm == vma.perm {
+                merged.end = next.end;
+                self.areas.remove(&vma.end);
+            }
+        }
+        if merged.start != vma.start {
+            self.areas.remove(&vma.start);
+        }
+        self.areas.insert(merged.start, merged);
+    }
+}
+
+/// Apply perm to the pages mapped or swapped out in [start, end) of pgdir
+///
+/// Copy-on-write pages stay read-only, the zero page stays read-only with
+/// `PteFlags::ZERO` marking it writable, and pages of the kernel image made writable
+/// are replaced by a private copy.
+pub fn protect_pages(
+    pgdir: PageDirectory,
+    asid: usize,
+    start: usize,
+    end: usize,
+    perm: PteFlags,
+) -> Result<(), MosError> {
+    const PERM_MASK: PteFlags = PteFlags::D.union(PteFlags::SHARED);
+    for va in (start..end).step_by(PAGE_SIZE) {
+        let Ok(Some(pte)) = pgdir.walk(VA(va), false) else {
+            continue;
+        };
+        if !pte.is_valid() && !pte.is_swapped() {
+            continue;
+        }
+        // the pages of a large page share one set of permissions
+        pgdir.demote(VA(va));
+        let mut flags = (pte.flags() - PERM_MASK) | (perm & PERM_MASK);
+        // a copy-on-write page may be shared with other envs, it is copied on the first
+        // write instead
+        if flags.contains(PteFlags::COW) {
+            flags -= PteFlags::D;
+        }
+        let page = Page::new(pte.ppn());
+        if pte.is_valid() && page.is_zero_page() {
+            flags = if flags.contains(PteFlags::D) {
+                (flags - PteFlags::D) | PteFlags::ZERO
+            } else {
+                flags - PteFlags::ZERO
+            };
+        }
+        if pte.is_valid() && flags.contains(PteFlags::D) && page.is_kernel_image() {
+            let copy = alloc_user_page(false).ok_or(MosError::NoMem)?;
+            copy_page(page, copy);
+            if let Err(err) = pgdir.insert(asid, copy, VA(va), flags) {
+                page_dealloc(copy);
+                return Err(err);
+            }
+            continue;
+        }
+        pte.set_flags(flags);
+        tlb_invalidate(asid, VA(va));
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! Kernel virtual memory in KSEG2.
+//!
+//! The kernel reaches memory through KSEG0, where virtual addresses follow physical
+//! ones, so a large buffer there needs physically contiguous pages. `vmalloc` instead
+//! maps pages from anywhere at consecutive addresses between `VMALLOC_START` and
+//! `VMALLOC_END`, in the page directory of `kseg2`.
+//!
+//! Every area is followed by an unmapped guard page, so running off its end faults
+//! instead of corrupting the next area.
+
+use super::{
+    addr::VA,
+    kseg2,
+    layout::{PteFlags, PAGE_SIZE, VMALLOC_END, VMALLOC_START},
+    page::{page_alloc_high, page_dealloc, Page},
+};
+use crate::mutex::{FakeLock, Mutex};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{mem::size_of, slice};
+use lazy_static::lazy_static;
+
+/// Areas handed out by `vmalloc`
+struct VmallocManager {
+    /// Pages of every area by start address
+    areas: BTreeMap<usize, Vec<Page>>,
+}
+
+impl VmallocManager {
+    /// Find a free range for an area of npages, guard page included
+    fn find_free(&self, npages: usize) -> Option<usize> {
+        let len = (npages + 1) * PAGE_SIZE;
+        let mut start = VMALLOC_START;
+        for (&va, pages) in self.areas.iter() {
+            if va - start >= len {
+                return Some(start);
+            }
+            start = va + (pages.len() + 1) * PAGE_SIZE;
+        }
+        (VMALLOC_END - start >= len).then_some(start)
+    }
+}
+
+lazy_static! {
+    static ref VMALLOC_MANAGER: FakeLock<VmallocManager> = FakeLock::new(VmallocManager {
+        areas: BTreeMap::new(),
+    });
+}
+
+/// Allocate size bytes of cleared, virtually contiguous kernel memory
+///
+/// # Returns
+///
+/// The page aligned start of the memory, `None` if size is 0 or if no pages or no
+/// virtual addresses are left.
+pub fn vmalloc(size: usize) -> Option<VA> {
+    let npages = size.div_ceil(PAGE_SIZE);
+    if npages == 0 {
+        return None;
+    }
+    let mut manager = VMALLOC_MANAGER.lock();
+    let start = manager.find_free(npages)?;
+    let pgdir = kseg2::pgdir();
+
+    let mut pages = Vec::with_capacity(npages);
+    for i in 0..npages {
+        let va = VA(start + i * PAGE_SIZE);
+        // the pages are only reached through KSEG2, they may as well be high memory
+        let mapped = page_alloc_high(true).map(|page| {
+            let result = pgdir.insert(0, page, va, PteFlags::G | PteFlags::D);
+            if result.is_err() {
+                page_dealloc(page);
+            }
+            (page, result)
+        });
+        match mapped {
+            Some((page, Ok(()))) => pages.push(page),
+            _ => {
+                (0..i).for_each(|j| pgdir.remove(0, VA(start + j * PAGE_SIZE)));
+                return None;
+            }
+        }
+    }
+    manager.areas.insert(start, pages);
+    Some(VA(start))
+}
+
+/// Free memory allocated by [`vmalloc`]
+///
+/// Panics if va is not the start of such memory
+pub fn vfree(va: VA) {
+    let mut manager = VMALLOC_MANAGER.lock();
+    let Some(pages) = manager.areas.remove(&va.0) else {
+        panic!("vfree: {:#010x} is not a vmalloc area", va.0);
+    };
+    let pgdir = kseg2::pgdir();
+    // removing a mapping also invalidates it in the TLB, global entries match any asid
+    (0..pages.len()).for_each(|i| pgdir.remove(0, va + i * PAGE_SIZE));
+}
+
+/// Check that a buffer of several pages can be allocated, written through and freed
+///
+/// Its pages are refilled into the TLB from the page directory of `kseg2` as they are
+/// touched.
+pub fn self_test() {
+    const PAGES: usize = 3;
+    let size = PAGES * PAGE_SIZE + 1;
+    let va = vmalloc(size).expect("vmalloc: no memory for the self test");
+    let words = size.div_ceil(size_of::<usize>());
+    let buf = unsafe { slice::from_raw_parts_mut(va.as_mut_ptr::<usize>(), words) };
+    assert!(
+        buf.iter().all(|&word| word == 0),
+        "vmalloc: memory not cleared"
+    );
+    buf.iter_mut().enumerate().for_each(|(i, word)| *word = i);
+    assert!(
+        buf.iter().enumerate().all(|(i, &word)| word == i),
+        "vmalloc: memory lost writes"
+    );
+    vfree(va);
+    let pgdir = kseg2::pgdir();
+    assert!(
+        (0..=PAGES).all(|i| pgdir.lookup(va + i * PAGE_SIZE).is_none()),
+        "vmalloc: memory still mapped after vfree"
+    );
+    // the freed range is handed out again
+    let again = vmalloc(size).expect("vmalloc: no memory for the self test");
+    assert_eq!(again.0, va.0, "vmalloc: freed range not reused");
+    vfree(again);
+}
@@ -13,7 +13,7 @@ use log::error;
 
 use crate::mutex::Mutex;
 use crate::{
-    mm::layout::{KSEG0, KSEG1}, platform::halt, pm::ENV_MANAGER, println
+    mm::layout::{KSEG0, KSEG1}, platform::{flush_output, halt}, pm::ENV_MANAGER, println
 };
 
 /// Panic
@@ -63,8 +63,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         "cur_pgdir: 0x{:08x}",
         ENV_MANAGER.lock().cur_pgdir().page.kaddr().0
     );
+    #[cfg(feature = "gdb_stub")]
+    crate::gdb::enter_on_panic();
     match option_env!("MOS_HANG_ON_PANIC") {
-        Some("1") => loop {},
+        Some("1") => {
+            flush_output();
+            loop {}
+        }
         _ => halt(),
     }
 }
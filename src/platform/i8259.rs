@@ -0,0 +1,115 @@
+//! Intel 8259 interrupt controller driver.
+//!
+//! The PIIX4 of the Malta board holds a master and a slave 8259, the slave cascaded on
+//! line 2 of the master, giving 16 IRQ lines. Their output reaches the CPU as hardware
+//! interrupt 0 (`STATUS_IM2`), and the GT-64120 acknowledges the pending interrupt and
+//! returns its vector on a read of `GT_PCI0_IACK`. Vectors are set up to equal IRQ
+//! numbers.
+
+use super::{
+    ioread_byte, ioread_word, iowrite_byte,
+    malta::{
+        GT_PCI0_IACK, PIC_CASCADE_IRQ, PIC_MASTER_CMD, PIC_MASTER_DATA, PIC_SLAVE_CMD,
+        PIC_SLAVE_DATA,
+    },
+};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// Number of IRQ lines
+pub const NIRQ: usize = 16;
+
+/// ICW1: edge triggered, cascaded, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+/// OCW2: non-specific end of interrupt
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: read the in-service register on the next read of the command port
+const OCW3_READ_ISR: u8 = 0x0b;
+
+/// IRQ lines masked, bit i for line i, all but the cascade at first
+static MASK: AtomicU16 = AtomicU16::new(!(1 << PIC_CASCADE_IRQ));
+
+/// Write the mask of both controllers
+fn write_mask(mask: u16) {
+    MASK.store(mask, Ordering::Relaxed);
+    unsafe {
+        iowrite_byte(PIC_MASTER_DATA, mask as u8);
+        iowrite_byte(PIC_SLAVE_DATA, (mask >> 8) as u8);
+    }
+}
+
+/// Initialize both controllers, with every line masked
+pub fn init() {
+    unsafe {
+        iowrite_byte(PIC_MASTER_CMD, ICW1_INIT);
+        iowrite_byte(PIC_SLAVE_CMD, ICW1_INIT);
+        // ICW2: vector bases
+        iowrite_byte(PIC_MASTER_DATA, 0);
+        iowrite_byte(PIC_SLAVE_DATA, 8);
+        // ICW3: where the slave is cascaded
+        iowrite_byte(PIC_MASTER_DATA, 1 << PIC_CASCADE_IRQ);
+        iowrite_byte(PIC_SLAVE_DATA, PIC_CASCADE_IRQ as u8);
+        iowrite_byte(PIC_MASTER_DATA, ICW4_8086);
+        iowrite_byte(PIC_SLAVE_DATA, ICW4_8086);
+    }
+    write_mask(MASK.load(Ordering::Relaxed));
+}
+
+/// Mask IRQ line irq
+pub fn mask(irq: usize) {
+    write_mask(MASK.load(Ordering::Relaxed) | 1 << irq);
+}
+
+/// Unmask IRQ line irq
+pub fn unmask(irq: usize) {
+    write_mask(MASK.load(Ordering::Relaxed) & !(1 << irq));
+}
+
+/// Check if IRQ line irq is masked
+pub fn is_masked(irq: usize) -> bool {
+    MASK.load(Ordering::Relaxed) & 1 << irq != 0
+}
+
+/// Acknowledge the pending interrupt
+///
+/// # Returns
+///
+/// The IRQ line that raised it
+pub fn read_irq() -> usize {
+    unsafe { ioread_word(GT_PCI0_IACK) as usize % NIRQ }
+}
+
+/// Check if irq, just acknowledged, is a spurious interrupt
+///
+/// A controller reports its lowest priority line, 7, when the request went away before
+/// it was acknowledged. Such an interrupt is not in service and must not be ended,
+/// except on the master for a spurious interrupt of the slave.
+pub fn is_spurious(irq: usize) -> bool {
+    if irq & 7 != 7 {
+        return false;
+    }
+    let cmd = if irq < 8 {
+        PIC_MASTER_CMD
+    } else {
+        PIC_SLAVE_CMD
+    };
+    let in_service = unsafe {
+        iowrite_byte(cmd, OCW3_READ_ISR);
+        ioread_byte(cmd) & 0x80 != 0
+    };
+    if !in_service && irq >= 8 {
+        unsafe { iowrite_byte(PIC_MASTER_CMD, OCW2_EOI) };
+    }
+    !in_service
+}
+
+/// End the interrupt of irq, letting its line and lower priority ones raise again
+pub fn eoi(irq: usize) {
+    unsafe {
+        if irq >= 8 {
+            iowrite_byte(PIC_SLAVE_CMD, OCW2_EOI);
+        }
+        iowrite_byte(PIC_MASTER_CMD, OCW2_EOI);
+    }
+}
@@ -0,0 +1,286 @@
+//! IDE disk driver.
+//!
+//! A minimal PIO driver for the PIIX4 IDE controller of the Malta board, used by the
+//! kernel itself (e.g. for swapping) on the other disk than the file system.
+//!
+//! The file system server drives disk 0 from user space, register by register through
+//! `sys_read_dev` and `sys_write_dev`, and may be switched out in the middle of a
+//! command. The kernel owns the controller so that its own I/O never comes in between:
+//! user accesses go to a copy of the registers, see `UserTaskfile`, and each sector of a
+//! user command is transferred at once by the kernel.
+
+use super::{
+    ioread_byte, ioread_word, iowrite_byte, iowrite_word,
+    malta::{
+        IDE_ABORT, IDE_BASE, IDE_BUSY, IDE_CMD_PIO_READ, IDE_CMD_PIO_WRITE, IDE_DATA, IDE_DEVICE,
+        IDE_DRQ, IDE_ERR, IDE_LBA, IDE_LBAH, IDE_LBAL, IDE_LBAM, IDE_NSECT, IDE_READY, IDE_STATUS,
+    },
+};
+use crate::{
+    error::MosError,
+    mutex::{FakeLock, Mutex},
+};
+use lazy_static::lazy_static;
+
+/// Size of a disk sector in bytes
+pub const SECT_SIZE: usize = 512;
+
+/// Status read from a floating bus, meaning no controller or disk is present
+const IDE_NO_DEVICE: u8 = 0xff;
+
+/// Wait until the controller is no longer busy
+///
+/// # Returns
+///
+/// The last status read, or `MosError::NotFound` if no disk responds.
+fn wait_ide_ready() -> Result<u8, MosError> {
+    loop {
+        let status = unsafe { ioread_byte(IDE_STATUS) };
+        if status == IDE_NO_DEVICE {
+            return Err(MosError::NotFound);
+        }
+        if status & IDE_BUSY == 0 {
+            return Ok(status);
+        }
+    }
+}
+
+/// Select `diskno` and set up a single sector transfer at `secno`
+fn ide_setup(diskno: u8, secno: u32, cmd: u8) -> Result<(), MosError> {
+    wait_ide_ready()?;
+    unsafe {
+        iowrite_byte(IDE_NSECT, 1);
+        iowrite_byte(IDE_LBAL, (secno & 0xff) as u8);
+        iowrite_byte(IDE_LBAM, ((secno >> 8) & 0xff) as u8);
+        iowrite_byte(IDE_LBAH, ((secno >> 16) & 0xff) as u8);
+        iowrite_byte(
+            IDE_DEVICE,
+            ((secno >> 24) & 0x0f) as u8 | IDE_LBA | (diskno << 4),
+        );
+        iowrite_byte(IDE_STATUS, cmd);
+    }
+    Ok(())
+}
+
+/// Check whether `diskno` is present
+///
+/// # Returns
+///
+/// `true` if the disk answered a one-sector read
+pub fn ide_probe(diskno: u8) -> bool {
+    let mut buf = [0; SECT_SIZE];
+    ide_read(diskno, 0, &mut buf).is_ok()
+}
+
+/// Read sectors starting at `secno` into `dst`
+///
+/// The length of `dst` must be a multiple of `SECT_SIZE`.
+///
+/// # Returns
+///
+/// `MosError::NotFound` if the disk is absent, `MosError::Inval` if the controller
+/// reported an error.
+pub fn ide_read(diskno: u8, secno: u32, dst: &mut [u8]) -> Result<(), MosError> {
+    assert!(diskno < 2, "ide_read: invalid disk {}", diskno);
+    assert_eq!(dst.len() % SECT_SIZE, 0, "ide_read: partial sector");
+    for (i, sector) in dst.chunks_exact_mut(SECT_SIZE).enumerate() {
+        ide_setup(diskno, secno + i as u32, IDE_CMD_PIO_READ)?;
+        if wait_ide_ready()? & IDE_ERR != 0 {
+            return Err(MosError::Inval);
+        }
+        for word in sector.chunks_exact_mut(4) {
+            word.copy_from_slice(&unsafe { ioread_word(IDE_DATA) }.to_ne_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Write `src` to sectors starting at `secno`
+///
+/// The length of `src` must be a multiple of `SECT_SIZE`.
+///
+/// # Returns
+///
+/// `MosError::NotFound` if the disk is absent, `MosError::Inval` if the controller
+/// reported an error.
+pub fn ide_write(diskno: u8, secno: u32, src: &[u8]) -> Result<(), MosError> {
+    assert!(diskno < 2, "ide_write: invalid disk {}", diskno);
+    assert_eq!(src.len() % SECT_SIZE, 0, "ide_write: partial sector");
+    for (i, sector) in src.chunks_exact(SECT_SIZE).enumerate() {
+        ide_setup(diskno, secno + i as u32, IDE_CMD_PIO_WRITE)?;
+        wait_ide_ready()?;
+        for word in sector.chunks_exact(4) {
+            let data = u32::from_ne_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe { iowrite_word(IDE_DATA, data) };
+        }
+        if wait_ide_ready()? & IDE_ERR != 0 {
+            return Err(MosError::Inval);
+        }
+    }
+    Ok(())
+}
+
+/// Registers of the controller as seen by user space
+struct UserTaskfile {
+    /// Sector count, LBA and device registers as last written, from `IDE_NSECT` on
+    regs: [u8; 5],
+    /// Command in progress, 0 if none
+    cmd: u8,
+    /// Set if the last command failed
+    error: bool,
+    /// Sector being transferred
+    buf: [u8; SECT_SIZE],
+    /// Bytes of buf transferred so far
+    pos: usize,
+    /// Sector number of buf
+    secno: u32,
+    /// Sectors of the command left, buf included
+    left: usize,
+}
+
+impl UserTaskfile {
+    const fn new() -> Self {
+        Self {
+            regs: [0; 5],
+            cmd: 0,
+            error: false,
+            buf: [0; SECT_SIZE],
+            pos: 0,
+            secno: 0,
+            left: 0,
+        }
+    }
+
+    fn reg(&self, pa: usize) -> u8 {
+        self.regs[pa - IDE_NSECT]
+    }
+
+    fn diskno(&self) -> u8 {
+        (self.reg(IDE_DEVICE) >> 4) & 1
+    }
+
+    fn status(&self) -> u8 {
+        let drq = if self.cmd != 0 { IDE_DRQ } else { 0 };
+        let err = if self.error { IDE_ERR } else { 0 };
+        IDE_READY | drq | err
+    }
+
+    fn fail(&mut self) {
+        self.cmd = 0;
+        self.error = true;
+    }
+
+    /// Start cmd on the sectors set up in the registers
+    fn start(&mut self, cmd: u8) {
+        let nsect = self.reg(IDE_NSECT) as usize;
+        self.left = if nsect == 0 { 256 } else { nsect };
+        self.secno = u32::from_le_bytes([
+            self.reg(IDE_LBAL),
+            self.reg(IDE_LBAM),
+            self.reg(IDE_LBAH),
+            self.reg(IDE_DEVICE) & 0x0f,
+        ]);
+        self.pos = 0;
+        self.error = false;
+        self.cmd = cmd;
+        match cmd {
+            IDE_CMD_PIO_READ => self.fill(),
+            IDE_CMD_PIO_WRITE => {}
+            _ => self.fail(),
+        }
+    }
+
+    /// Read the next sector of a read command
+    fn fill(&mut self) {
+        if ide_read(self.diskno(), self.secno, &mut self.buf).is_err() {
+            self.fail();
+        }
+    }
+
+    /// Move data between the data register and buf, write is set for a write
+    fn transfer(&mut self, data: &mut [u8], write: bool) {
+        let cmd = if write {
+            IDE_CMD_PIO_WRITE
+        } else {
+            IDE_CMD_PIO_READ
+        };
+        if self.cmd != cmd {
+            return;
+        }
+        let len = data.len().min(SECT_SIZE - self.pos);
+        let range = self.pos..self.pos + len;
+        if write {
+            self.buf[range].copy_from_slice(&data[..len]);
+        } else {
+            data[..len].copy_from_slice(&self.buf[range]);
+        }
+        self.pos += len;
+        if self.pos < SECT_SIZE {
+            return;
+        }
+        if write && ide_write(self.diskno(), self.secno, &self.buf).is_err() {
+            self.fail();
+            return;
+        }
+        self.pos = 0;
+        self.secno += 1;
+        self.left -= 1;
+        if self.left == 0 {
+            self.cmd = 0;
+        } else if !write {
+            self.fill();
+        }
+    }
+}
+
+lazy_static! {
+    static ref USER_TASKFILE: FakeLock<UserTaskfile> = FakeLock::new(UserTaskfile::new());
+}
+
+/// Check if the device address pa is a register of the IDE controller
+pub fn is_ide_reg(pa: usize) -> bool {
+    (IDE_BASE..=IDE_STATUS).contains(&pa)
+}
+
+/// Read registers from pa on for user space, a read of `IDE_DATA` takes data only
+pub fn user_read(pa: usize, dst: &mut [u8]) {
+    let mut taskfile = USER_TASKFILE.lock();
+    if pa == IDE_DATA {
+        dst.fill(0);
+        taskfile.transfer(dst, false);
+        return;
+    }
+    for (i, byte) in dst.iter_mut().enumerate() {
+        *byte = match pa + i {
+            IDE_STATUS => taskfile.status(),
+            reg if reg >= IDE_NSECT => taskfile.reg(reg),
+            // the error register
+            _ if taskfile.error => IDE_ABORT,
+            _ => 0,
+        };
+    }
+}
+
+/// Write registers from pa on for user space, a write of `IDE_DATA` gives data only
+///
+/// Accesses are at most 4 bytes long.
+///
+/// A command written to `IDE_STATUS` reads its first sector before returning.
+pub fn user_write(pa: usize, src: &[u8]) {
+    let mut taskfile = USER_TASKFILE.lock();
+    if pa == IDE_DATA {
+        let mut data = [0; 4];
+        let data = &mut data[..src.len()];
+        data.copy_from_slice(src);
+        taskfile.transfer(data, true);
+        return;
+    }
+    for (i, &byte) in src.iter().enumerate() {
+        match pa + i {
+            IDE_STATUS => taskfile.start(byte),
+            reg if reg >= IDE_NSECT => taskfile.regs[reg - IDE_NSECT] = byte,
+            // the features register, no command here uses it
+            _ => {}
+        }
+    }
+}
@@ -1,7 +1,20 @@
 //! Machine-specific functions.
+//!
+//! The 16550 UART is interrupt driven once `uart_init` has been called: received bytes
+//! are queued by `uart_intr` until `read_char` takes them, and bytes to send are queued
+//! by `print_char` while the transmitter is busy, to be written out by `uart_intr`
+//! whenever its FIFO empties.
 
-use super::malta::{FPGA_HALT, SERIAL_DATA, SERIAL_DATA_READY, SERIAL_LSR, SERIAL_THR_EMPTY};
-use crate::mm::layout::KSEG1;
+use super::malta::{
+    FPGA_HALT, SERIAL_DATA, SERIAL_DATA_READY, SERIAL_FCR, SERIAL_FCR_ENABLE, SERIAL_IER,
+    SERIAL_IER_RX, SERIAL_IER_TX, SERIAL_LSR, SERIAL_MCR, SERIAL_MCR_INTR, SERIAL_THR_EMPTY,
+    SERIAL_TX_FIFO,
+};
+use crate::{
+    mm::layout::KSEG1,
+    mutex::{FakeLock, Mutex},
+};
+use lazy_static::lazy_static;
 
 /// Reads a byte from the specified address.
 ///
@@ -106,6 +119,149 @@ pub unsafe fn iowrite_word(pa: usize, data: u32) {
     ptr.write_volatile(data);
 }
 
+/// Fixed size byte queue
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append byte, `false` if the queue is full
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Bytes received and not read yet
+const RX_BUFFER_SIZE: usize = 256;
+/// Bytes waiting for the transmitter
+const TX_BUFFER_SIZE: usize = 1024;
+
+/// Queues of the UART
+struct Uart {
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    /// Set once interrupts are enabled, bytes are only queued from then on
+    interrupts: bool,
+}
+
+lazy_static! {
+    static ref UART: FakeLock<Uart> = FakeLock::new(Uart {
+        rx: RingBuffer::new(),
+        tx: RingBuffer::new(),
+        interrupts: false,
+    });
+}
+
+/// Check if the transmitter can take another FIFO load
+fn tx_ready() -> bool {
+    unsafe { ioread_byte(SERIAL_LSR) & SERIAL_THR_EMPTY != 0 }
+}
+
+/// Move queued bytes to the transmitter while it has room
+///
+/// The THR empty interrupt is enabled as long as bytes are left in the queue.
+fn uart_tx(uart: &mut Uart) {
+    if tx_ready() {
+        for _ in 0..SERIAL_TX_FIFO {
+            let Some(byte) = uart.tx.pop() else {
+                break;
+            };
+            unsafe { iowrite_byte(SERIAL_DATA, byte) };
+        }
+    }
+    if uart.interrupts {
+        let ier = if uart.tx.is_empty() {
+            SERIAL_IER_RX
+        } else {
+            SERIAL_IER_RX | SERIAL_IER_TX
+        };
+        unsafe { iowrite_byte(SERIAL_IER, ier) };
+    }
+}
+
+/// Enable the FIFOs and the receive interrupt of the UART
+///
+/// The caller is expected to route its IRQ line to `uart_intr`.
+pub fn uart_init() {
+    unsafe {
+        iowrite_byte(SERIAL_FCR, SERIAL_FCR_ENABLE);
+        iowrite_byte(SERIAL_MCR, SERIAL_MCR_INTR);
+        iowrite_byte(SERIAL_IER, SERIAL_IER_RX);
+    }
+    UART.lock().interrupts = true;
+}
+
+/// Serve an interrupt of the UART
+///
+/// # Returns
+///
+/// `true` if bytes were received
+pub fn uart_intr() -> bool {
+    let mut uart = UART.lock();
+    let mut received = false;
+    while unsafe { ioread_byte(SERIAL_LSR) } & SERIAL_DATA_READY != 0 {
+        // bytes are dropped while the receive queue is full
+        let byte = unsafe { ioread_byte(SERIAL_DATA) };
+        received |= uart.rx.push(byte);
+    }
+    uart_tx(&mut uart);
+    received
+}
+
+/// Queue a byte for the transmitter, waiting for it only if the queue is full
+pub fn put_byte(byte: u8) {
+    let mut uart = UART.lock();
+    if !uart.interrupts {
+        while !tx_ready() {}
+        unsafe { iowrite_byte(SERIAL_DATA, byte) };
+        return;
+    }
+    while !uart.tx.push(byte) {
+        // interrupts may be disabled, drain the queue by hand
+        while !tx_ready() {}
+        uart_tx(&mut uart);
+    }
+    uart_tx(&mut uart);
+}
+
+/// Write out every queued byte, waiting for the transmitter
+pub fn flush_output() {
+    let mut uart = UART.lock();
+    while !uart.tx.is_empty() {
+        while !tx_ready() {}
+        uart_tx(&mut uart);
+    }
+}
+
 /// Prints a character to the serial port.
 ///
 /// # Arguments
@@ -115,16 +271,13 @@ pub fn print_char(c: char) {
     if c == '\n' {
         print_char('\r');
     }
-    unsafe {
-        while ioread_byte(SERIAL_LSR) & SERIAL_THR_EMPTY == 0 {}
-        if c.is_ascii() {
-            iowrite_byte(SERIAL_DATA, c as u8);
-        } else {
-            let mut dst = [0; 4];
-            c.encode_utf8(&mut dst);
-            for &byte in dst.iter() {
-                iowrite_byte(SERIAL_DATA, byte);
-            }
+    if c.is_ascii() {
+        put_byte(c as u8);
+    } else {
+        let mut dst = [0; 4];
+        c.encode_utf8(&mut dst);
+        for &byte in dst.iter() {
+            put_byte(byte);
         }
     }
 }
@@ -137,6 +290,10 @@ pub fn print_char(c: char) {
 ///
 /// The character read from the serial port, or '\0' if no character is available.
 pub fn read_char() -> char {
+    if let Some(byte) = UART.lock().rx.pop() {
+        return byte as char;
+    }
+    // interrupts are only taken in user mode, the byte may still wait in the UART
     unsafe {
         if ioread_byte(SERIAL_LSR) & SERIAL_DATA_READY != 0 {
             ioread_byte(SERIAL_DATA) as char
@@ -150,7 +307,9 @@ pub fn read_char() -> char {
 ///
 /// This function writes a specific value to the `FPGA_HALT` address, causing the system to halt.
 /// If halting is not supported on the current platform, this function will loop indefinitely.
+/// Queued console output is written out first.
 pub fn halt() -> ! {
+    flush_output();
     unsafe {
         iowrite_byte(FPGA_HALT, 0x42);
     }
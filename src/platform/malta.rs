@@ -5,8 +5,43 @@
  * QEMU MMIO address definitions.
  */
 const PCIIO_BASE: usize = 0x18000000;
+const GT_BASE: usize = 0x1be00000;
 const FPGA_BASE: usize = 0x1f000000;
 
+/*
+ * GT-64120 system controller definitions.
+ */
+/// PCI interrupt acknowledge, reading it returns the vector of the pending i8259 interrupt
+pub const GT_PCI0_IACK: usize = GT_BASE + 0xc34;
+
+/*
+ * Intel 8259 interrupt controller pair of the PIIX4.
+ */
+/// Master PIC command
+pub const PIC_MASTER_CMD: usize = PCIIO_BASE + 0x20;
+/// Master PIC data
+pub const PIC_MASTER_DATA: usize = PCIIO_BASE + 0x21;
+/// Slave PIC command
+pub const PIC_SLAVE_CMD: usize = PCIIO_BASE + 0xa0;
+/// Slave PIC data
+pub const PIC_SLAVE_DATA: usize = PCIIO_BASE + 0xa1;
+/// Master PIC line the slave is cascaded on
+pub const PIC_CASCADE_IRQ: usize = 2;
+
+/*
+ * MC146818 real time clock of the PIIX4.
+ */
+/// RTC register index
+pub const RTC_ADDR: usize = PCIIO_BASE + 0x70;
+/// RTC register data
+pub const RTC_DATA: usize = PCIIO_BASE + 0x71;
+/// RTC register A
+pub const RTC_REG_A: u8 = 0x0a;
+/// Register A: 32.768 kHz time base, 1024 Hz periodic rate
+pub const RTC_REG_A_INIT: u8 = 0x26;
+/// Register A: update in progress, set once a second for the update of the time
+pub const RTC_UIP: u8 = 0x80;
+
 /*
  * 16550 Serial UART device definitions.
  */
@@ -14,30 +49,70 @@ const FPGA_BASE: usize = 0x1f000000;
 pub const SERIAL_BASE: usize = PCIIO_BASE + 0x3f8;
 /// Serial data
 pub const SERIAL_DATA: usize = SERIAL_BASE;
+/// Serial interrupt enable register
+pub const SERIAL_IER: usize = SERIAL_BASE + 0x1;
+/// Serial FIFO control register
+pub const SERIAL_FCR: usize = SERIAL_BASE + 0x2;
+/// Serial modem control register
+pub const SERIAL_MCR: usize = SERIAL_BASE + 0x4;
 /// Serial lsr
 pub const SERIAL_LSR: usize = SERIAL_BASE + 0x5;
 /// Serial data ready
 pub const SERIAL_DATA_READY: u8 = 0x1;
 /// Serial thr empty
 pub const SERIAL_THR_EMPTY: u8 = 0x20;
+/// Serial interrupt on received data
+pub const SERIAL_IER_RX: u8 = 0x1;
+/// Serial interrupt on thr empty
+pub const SERIAL_IER_TX: u8 = 0x2;
+/// Serial FIFOs enabled and cleared
+pub const SERIAL_FCR_ENABLE: u8 = 0x7;
+/// Serial DTR, RTS and OUT2, which gates the interrupt line
+pub const SERIAL_MCR_INTR: u8 = 0xb;
+/// Serial transmit FIFO depth
+pub const SERIAL_TX_FIFO: usize = 16;
+/// Serial IRQ line on the i8259
+pub const SERIAL_IRQ: usize = 4;
+/// Second serial base, with the registers of the first one at the same offsets
+#[cfg(feature = "gdb_stub")]
+pub const SERIAL2_BASE: usize = PCIIO_BASE + 0x2f8;
 
 /*
  * Intel PIIX4 IDE Controller device definitions.
  */
 /// IDE base
 pub const IDE_BASE: usize = PCIIO_BASE + 0x01f0;
-// pub const IDE_DATA: usize = IDE_BASE;
+/// IDE data
+pub const IDE_DATA: usize = IDE_BASE;
 // pub const IDE_ERR: usize = IDE_BASE + 0x01;
-// pub const IDE_NSECT: usize = IDE_BASE + 0x02;
-// pub const IDE_LBAL: usize = IDE_BASE + 0x03;
-// pub const IDE_LBAM: usize = IDE_BASE + 0x04;
-// pub const IDE_LBAH: usize = IDE_BASE + 0x05;
-// pub const IDE_DEVICE: usize = IDE_BASE + 0x06;
-// pub const IDE_STATUS: usize = IDE_BASE + 0x07;
-// pub const IDE_LBA: u8 = 0xE0;
-// pub const IDE_BUSY: u8 = 0x80;
-// pub const IDE_CMD_PIO_READ: u8 = 0x20;
-// pub const IDE_CMD_PIO_WRITE: u8 = 0x30;
+/// IDE sector count
+pub const IDE_NSECT: usize = IDE_BASE + 0x02;
+/// IDE LBA bits 0-7
+pub const IDE_LBAL: usize = IDE_BASE + 0x03;
+/// IDE LBA bits 8-15
+pub const IDE_LBAM: usize = IDE_BASE + 0x04;
+/// IDE LBA bits 16-23
+pub const IDE_LBAH: usize = IDE_BASE + 0x05;
+/// IDE device and LBA bits 24-27
+pub const IDE_DEVICE: usize = IDE_BASE + 0x06;
+/// IDE status, also the command register on write
+pub const IDE_STATUS: usize = IDE_BASE + 0x07;
+/// IDE LBA mode
+pub const IDE_LBA: u8 = 0xE0;
+/// IDE busy
+pub const IDE_BUSY: u8 = 0x80;
+/// IDE error
+pub const IDE_ERR: u8 = 0x01;
+/// IDE data request, data is ready to be transferred
+pub const IDE_DRQ: u8 = 0x08;
+/// IDE device ready
+pub const IDE_READY: u8 = 0x40;
+/// IDE error register: command aborted
+pub const IDE_ABORT: u8 = 0x04;
+/// IDE PIO read command
+pub const IDE_CMD_PIO_READ: u8 = 0x20;
+/// IDE PIO write command
+pub const IDE_CMD_PIO_WRITE: u8 = 0x30;
 
 /*
  * MALTA Power Management device definitions.
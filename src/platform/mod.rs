@@ -1,6 +1,8 @@
 //! Platform constants
 
 pub mod cp0reg;
+pub mod i8259;
+pub mod ide;
 mod machine;
 pub mod malta;
 
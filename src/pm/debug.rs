@@ -0,0 +1,337 @@
+//! Debugging of envs by other envs.
+//!
+//! An env attaches to a child of its own with `sys_debug_attach` and becomes its tracer.
+//! A runnable tracee is stopped on attach. From then on, the exceptions of the tracee in
+//! user mode that would go to its handler or kill it stop it instead, and are reported
+//! to the tracer by IPC from the tracee: the value is the ExcCode of the exception, or
+//! one of the `DEBUG_STOP_*` reports. Reports sent while the tracer is not receiving are
+//! kept until it receives.
+//!
+//! Those are breakpoints, reserved instructions, overflows and traps, address errors,
+//! coprocessor unusable exceptions the FPU does not serve, TLB faults outside every
+//! area, watchpoint hits and any exception without a handler. TLB misses and
+//! copy-on-write faults the kernel or the user handler serve are not reported, nor are
+//! interrupts and syscalls.
+//!
+//! With `sys_debug_op`, the tracer reads and writes the registers and the memory of the
+//! tracee, and resumes a stopped tracee by continuing or single-stepping it. Stepping
+//! plants temporary `break`s on every instruction that may come next, which are removed
+//! on the next stop. An exception the tracer lets the tracee go on from without moving
+//! its EPC is raised again, and stops it again unless the tracer asked to deliver it.
+
+use super::{ipc_send_value, schedule, Env, EnvStatus, ENV_MANAGER};
+use crate::{
+    error::MosError,
+    exception::{
+        insn::{step_targets, Insn, BREAK},
+        Trapframe,
+    },
+    mm::{
+        cache::sync_icache,
+        highmem::kmap,
+        layout::{is_illegal_user_va, PAGE_SIZE},
+        VA,
+    },
+    mutex::Mutex,
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use log::info;
+
+/// `sys_debug_op`: copy the registers of the tracee to buf
+pub const DEBUG_GET_REGS: u32 = 0;
+/// `sys_debug_op`: set the registers of the tracee from buf, but the status register
+pub const DEBUG_SET_REGS: u32 = 1;
+/// `sys_debug_op`: copy len bytes at va of the tracee to buf
+pub const DEBUG_READ: u32 = 2;
+/// `sys_debug_op`: copy len bytes of buf to va of the tracee
+pub const DEBUG_WRITE: u32 = 3;
+/// `sys_debug_op`: resume the stopped tracee, delivering the exception it stopped on if
+/// arg is not 0
+pub const DEBUG_CONTINUE: u32 = 4;
+/// `sys_debug_op`: execute one instruction of the stopped tracee, delivering the
+/// exception it stopped on if arg is not 0
+pub const DEBUG_STEP: u32 = 5;
+/// `sys_debug_op`: stop tracing, a stopped tracee is resumed
+pub const DEBUG_DETACH: u32 = 6;
+
+/// Stop report: the tracee was stopped on attach
+pub const DEBUG_STOP_ATTACH: u32 = 0x100;
+/// Stop report: a single step is done
+pub const DEBUG_STOP_STEP: u32 = 0x101;
+/// Report: the tracee is gone, nothing can be done with it anymore
+pub const DEBUG_EXITED: u32 = 0x102;
+
+/// ExcCode of breakpoint exceptions
+const EXC_BP: u32 = 9;
+
+/// State of a traced env
+#[derive(Debug)]
+pub struct Trace {
+    /// Env debugging it, by id
+    tracer: usize,
+    /// Stopped until its tracer resumes it
+    stopped: bool,
+    /// Let the next exception go to its handler
+    deliver: bool,
+    /// `break`s planted for a single step, with the instructions they replaced
+    step: Vec<(usize, u32)>,
+}
+
+/// Debugging state of an env, as a tracee and as a tracer
+#[derive(Debug)]
+pub struct DebugState {
+    /// Set while the env is traced
+    trace: Option<Trace>,
+    /// Envs traced by this one, by id
+    tracees: Vec<usize>,
+    /// Reports of tracees not received yet, with the tracee id
+    reports: VecDeque<(usize, u32)>,
+}
+
+impl DebugState {
+    /// Create the state of an env neither traced nor tracing
+    pub const fn new() -> Self {
+        Self {
+            trace: None,
+            tracees: Vec::new(),
+            reports: VecDeque::new(),
+        }
+    }
+}
+
+/// Check if env is traced by the env of id tracer
+pub fn is_traced_by(env: &Env, tracer: usize) -> bool {
+    matches!(&env.ext().debug.trace, Some(trace) if trace.tracer == tracer)
+}
+
+/// Send report about tracee to the env of id tracer, or keep it until it receives
+fn report(tracer: usize, tracee: usize, value: u32) {
+    let Ok(tracer) = ENV_MANAGER.lock().env_from_id(tracer, false) else {
+        return;
+    };
+    if ipc_send_value(tracer, tracee, value).is_err() {
+        tracer.ext().debug.reports.push_back((tracee, value));
+    }
+}
+
+/// Check if env is stopped until its tracer resumes it
+pub fn is_stopped(env: &Env) -> bool {
+    matches!(&env.ext().debug.trace, Some(trace) if trace.stopped)
+}
+
+/// Take the oldest report env has not received yet
+///
+/// # Returns
+///
+/// The tracee id and the report, None if there is none
+pub fn take_report(env: &Env) -> Option<(usize, u32)> {
+    env.ext().debug.reports.pop_front()
+}
+
+/// Stop env, traced, and report value to its tracer
+fn stop(env: &mut Env, value: u32) {
+    let Some(trace) = env.ext().debug.trace.as_mut() else {
+        return;
+    };
+    trace.stopped = true;
+    env.status = EnvStatus::NotRunnable;
+    ENV_MANAGER.lock().remove_from_schedule(env.id);
+    report(trace.tracer, env.id, value);
+}
+
+/// Make tracer the tracer of tracee
+///
+/// # Returns
+///
+/// `MosError::BadEnv` if tracee is already traced or is tracer itself
+pub fn attach(tracer: &mut Env, tracee: &mut Env) -> Result<(), MosError> {
+    if tracee.id == tracer.id || tracee.ext().debug.trace.is_some() {
+        return Err(MosError::BadEnv);
+    }
+    tracee.ext().debug.trace = Some(Trace {
+        tracer: tracer.id,
+        stopped: false,
+        deliver: false,
+        step: Vec::new(),
+    });
+    tracer.ext().debug.tracees.push(tracee.id);
+    info!("{:08x}: attached to {:08x}", tracer.id, tracee.id);
+    // a blocked tracee stops on its next exception
+    if tracee.status == EnvStatus::Runnable {
+        stop(tracee, DEBUG_STOP_ATTACH);
+    }
+    Ok(())
+}
+
+/// Stop tracing env, which is resumed if stopped
+pub fn detach(env: &mut Env) {
+    let Some(trace) = env.ext().debug.trace.take() else {
+        return;
+    };
+    remove_step(env, &trace.step);
+    if trace.stopped {
+        env.wake();
+    }
+    if let Ok(tracer) = ENV_MANAGER.lock().env_from_id(trace.tracer, false) {
+        let debug = &mut tracer.ext().debug;
+        debug.tracees.retain(|&id| id != env.id);
+        debug.reports.retain(|&(id, _)| id != env.id);
+    }
+}
+
+/// Detach env, which is being freed, from its tracer and from its tracees
+///
+/// The tracer is told env is gone.
+pub fn release(env: &mut Env) {
+    let debug = &mut env.ext().debug;
+    if let Some(trace) = debug.trace.take() {
+        if let Ok(tracer) = ENV_MANAGER.lock().env_from_id(trace.tracer, false) {
+            tracer.ext().debug.tracees.retain(|&id| id != env.id);
+        }
+        report(trace.tracer, env.id, DEBUG_EXITED);
+    }
+    for id in core::mem::take(&mut debug.tracees) {
+        if let Ok(tracee) = ENV_MANAGER.lock().env_from_id(id, false) {
+            detach(tracee);
+        }
+    }
+}
+
+/// Stop env, the current env, on the exception of tf in user mode if it is traced
+///
+/// Returns if env is not traced or the exception is to be delivered, and never returns
+/// otherwise.
+pub fn stop_on_exception(env: &mut Env, tf: &Trapframe) {
+    let Some(trace) = env.ext().debug.trace.as_mut() else {
+        return;
+    };
+    if core::mem::take(&mut trace.deliver) {
+        return;
+    }
+    let step = core::mem::take(&mut trace.step);
+    remove_step(env, &step);
+    let code = (tf.cp0_cause >> 2) & 0x1f;
+    let stepped = code == EXC_BP && step.iter().any(|&(va, _)| va == tf.cp0_epc as usize);
+    stop(env, if stepped { DEBUG_STOP_STEP } else { code });
+    schedule(true);
+}
+
+/// Resume env, traced and stopped, for a single step if step is set
+///
+/// # Returns
+///
+/// `MosError::Inval` if env is not stopped, or if the instructions that may come next
+/// cannot all be reached
+pub fn resume(env: &mut Env, step: bool, deliver: bool) -> Result<(), MosError> {
+    let Some(trace) = env.ext().debug.trace.as_mut() else {
+        return Err(MosError::BadEnv);
+    };
+    if !trace.stopped {
+        return Err(MosError::Inval);
+    }
+    if step {
+        trace.step = plant_step(env)?;
+    }
+    trace.deliver = deliver;
+    trace.stopped = false;
+    env.wake();
+    Ok(())
+}
+
+/// Plant a `break` on every instruction that may run after the one at the EPC of env
+///
+/// # Returns
+///
+/// The addresses planted and the instructions they replaced, nothing stays planted on
+/// failure
+fn plant_step(env: &mut Env) -> Result<Vec<(usize, u32)>, MosError> {
+    let pc = env.tf.cp0_epc;
+    let insn = Insn(read_word(env, pc as usize)?);
+    let targets = step_targets(&env.tf, pc, insn);
+    let mut planted = Vec::new();
+    for va in targets.into_iter().flatten().map(|va| va as usize) {
+        if planted.iter().any(|&(planted, _)| planted == va) {
+            continue;
+        }
+        let result = read_word(env, va).and_then(|insn| {
+            write_memory(env, va, &BREAK.to_le_bytes())?;
+            Ok(insn)
+        });
+        match result {
+            Ok(insn) => planted.push((va, insn)),
+            Err(err) => {
+                remove_step(env, &planted);
+                return Err(err);
+            }
+        }
+    }
+    Ok(planted)
+}
+
+/// Put back the instructions replaced by the `break`s of step
+fn remove_step(env: &mut Env, step: &[(usize, u32)]) {
+    for &(va, insn) in step.iter().rev() {
+        let _ = write_memory(env, va, &insn.to_le_bytes());
+    }
+}
+
+/// Run f on the bytes of the page of env at va, brought in first
+///
+/// The page is made private to env before it is written.
+fn with_page<T>(
+    env: &mut Env,
+    va: usize,
+    write: bool,
+    f: impl FnOnce(&mut [u8]) -> T,
+) -> Result<T, MosError> {
+    if is_illegal_user_va(va) {
+        return Err(MosError::Inval);
+    }
+    let va = VA(va);
+    if !env.page_in(va)? {
+        return Err(MosError::Inval);
+    }
+    let page = if write {
+        env.pgdir().make_private(env.asid, va)?
+    } else {
+        env.pgdir().lookup(va).ok_or(MosError::Inval)?.1
+    };
+    Ok(f(kmap(page).bytes_mut()))
+}
+
+/// Copy bytes of env from va to buf
+pub fn read_memory(env: &mut Env, va: usize, buf: &mut [u8]) -> Result<(), MosError> {
+    let mut done = 0;
+    while done < buf.len() {
+        let offset = (va + done) % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(buf.len() - done);
+        with_page(env, va + done, false, |page| {
+            buf[done..done + len].copy_from_slice(&page[offset..offset + len]);
+        })?;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Copy data to va of env, even to read-only pages
+///
+/// The data may be instructions, they are fetched afterwards.
+pub fn write_memory(env: &mut Env, va: usize, data: &[u8]) -> Result<(), MosError> {
+    let mut done = 0;
+    while done < data.len() {
+        let offset = (va + done) % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(data.len() - done);
+        with_page(env, va + done, true, |page| {
+            page[offset..offset + len].copy_from_slice(&data[done..done + len]);
+            sync_icache(va + done, page[offset..].as_ptr() as usize, len);
+        })?;
+        done += len;
+    }
+    Ok(())
+}
+
+fn read_word(env: &mut Env, va: usize) -> Result<u32, MosError> {
+    let mut bytes = [0; 4];
+    read_memory(env, va, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
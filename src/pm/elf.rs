@@ -3,13 +3,16 @@ use super::env::Env;
 use crate::{
     error::MosError,
     mm::{
-        layout::{PteFlags, PAGE_SIZE},
-        page::page_alloc,
+        highmem::kmap,
+        layout::{PteFlags, KSEG0, KSEG1, PAGE_SIZE},
+        page::{page_dealloc, zero_page, Page},
+        swap::alloc_user_page,
         VA,
     },
     round_down,
 };
-use core::{cmp::min, ptr::copy_nonoverlapping};
+use alloc::vec::Vec;
+use core::cmp::min;
 
 pub const EI_INDENT: usize = 16;
 
@@ -85,7 +88,45 @@ pub const PF_W: u32 = 1 << 1;
 // pub const PF_R: u32 = 1 << 2;
 // pub const PF_MASKPROC: u32 = 0xf0000000;
 
-pub type ElfMapperFn = fn(&mut Env, VA, usize, PteFlags, Option<&[u8]>) -> Result<(), MosError>;
+pub type ElfMapperFn<'a> =
+    fn(&mut Env, VA, usize, PteFlags, Option<&'a [u8]>) -> Result<(), MosError>;
+
+/// A user page to be filled from an ELF image on first access
+#[derive(Clone, Debug)]
+pub struct LazyPage {
+    perm: PteFlags,
+    /// Offsets in the page and the data copied there, the rest of the page is zero
+    chunks: Vec<(usize, &'static [u8])>,
+}
+
+impl LazyPage {
+    /// Set the permission the page will be mapped with
+    pub fn set_perm(&mut self, perm: PteFlags) {
+        self.perm = (self.perm - PteFlags::D - PteFlags::SHARED) | perm;
+    }
+
+    /// Acquire the page of the image backing this page entirely
+    ///
+    /// Read-only pages whose content is a whole page-aligned page of an image in
+    /// kernel memory can be mapped directly instead of being copied, pages without
+    /// content (e.g. `.bss`) start out as the zero page.
+    fn image_page(&self) -> Option<Page> {
+        if self.chunks.is_empty() {
+            return zero_page();
+        }
+        if self.perm.contains(PteFlags::D) {
+            return None;
+        }
+        match self.chunks.as_slice() {
+            [(0, data)] if data.len() == PAGE_SIZE => {
+                let addr = data.as_ptr() as usize;
+                (addr & (PAGE_SIZE - 1) == 0 && (KSEG0..KSEG1).contains(&addr))
+                    .then(|| Page::from(VA(addr).paddr()))
+            }
+            _ => None,
+        }
+    }
+}
 
 impl<'a> Elf32<'a> {
     /// check if file is elf32 format
@@ -137,10 +178,10 @@ impl<'a> Elf32<'a> {
 /// # Returns
 ///
 /// Ok(()) on success, MosError on failure
-pub fn elf_load_seg(
+pub fn elf_load_seg<'a>(
     ph: &Elf32Phdr,
-    bin: &[u8],
-    map_page: ElfMapperFn,
+    bin: &'a [u8],
+    map_page: ElfMapperFn<'a>,
     env: &mut Env,
 ) -> Result<(), MosError> {
     let va: VA = VA(ph.p_vaddr as usize);
@@ -164,7 +205,7 @@ pub fn elf_load_seg(
     };
 
     while i < bin_size {
-        let len = min(sg_size - i, PAGE_SIZE);
+        let len = min(bin_size - i, PAGE_SIZE);
         map_page(env, va + i, 0, perm, Some(&bin[i..i + len]))?;
         i += PAGE_SIZE;
     }
@@ -183,6 +224,7 @@ pub fn elf_load_seg(
 /// # Returns
 ///
 /// Ok(()) on success, MosError on failure
+#[allow(dead_code)]
 pub fn load_icode_mapper(
     env: &mut Env,
     va: VA,
@@ -190,16 +232,69 @@ pub fn load_icode_mapper(
     perm: PteFlags,
     src: Option<&[u8]>,
 ) -> Result<(), MosError> {
-    let page = page_alloc(true).unwrap();
+    let page = alloc_user_page(true).ok_or(MosError::NoMem)?;
 
     if let Some(data) = src {
-        unsafe {
-            copy_nonoverlapping(
-                data.as_ptr(),
-                (page.kaddr() + offset).0 as *mut u8,
-                data.len(),
-            );
-        }
+        kmap(page).bytes_mut()[offset..offset + data.len()].copy_from_slice(data);
     }
     env.pgdir().insert(env.asid, page, va, perm)
 }
+
+/// Record a page of an ELF segment in the env, to be loaded by `lazy_load` on first access.
+/// Same arguments as `load_icode_mapper`.
+///
+/// # Returns
+///
+/// Ok(()) on success, MosError on failure
+pub fn lazy_icode_mapper(
+    env: &mut Env,
+    va: VA,
+    offset: usize,
+    perm: PteFlags,
+    src: Option<&'static [u8]>,
+) -> Result<(), MosError> {
+    let page = env
+        .ext()
+        .lazy_pages
+        .entry(round_down!(va.0, PAGE_SIZE))
+        .or_insert(LazyPage {
+            perm: PteFlags::empty(),
+            chunks: Vec::new(),
+        });
+    page.perm |= perm;
+    if let Some(data) = src {
+        page.chunks.push((offset, data));
+    }
+    Ok(())
+}
+
+/// Fill in the page at va of env if it is part of an ELF image not loaded yet
+///
+/// # Returns
+///
+/// Ok(true) if a page has been mapped at va, Ok(false) if va is not lazily loaded,
+/// MosError on failure
+pub fn lazy_load(env: &mut Env, va: VA) -> Result<bool, MosError> {
+    let va = VA(round_down!(va.0, PAGE_SIZE));
+    let Some(lazy) = env.ext().lazy_pages.remove(&va.0) else {
+        return Ok(false);
+    };
+    if let Some(page) = lazy.image_page() {
+        env.pgdir().insert(env.asid, page, va, lazy.perm)?;
+        return Ok(true);
+    }
+    let Some(page) = alloc_user_page(true) else {
+        env.ext().lazy_pages.insert(va.0, lazy);
+        return Err(MosError::NoMem);
+    };
+    let mut map = kmap(page);
+    for &(offset, data) in lazy.chunks.iter() {
+        map.bytes_mut()[offset..offset + data.len()].copy_from_slice(data);
+    }
+    if let Err(err) = env.pgdir().insert(env.asid, page, va, lazy.perm) {
+        page_dealloc(page);
+        env.ext().lazy_pages.insert(va.0, lazy);
+        return Err(err);
+    }
+    Ok(true)
+}
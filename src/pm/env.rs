@@ -1,29 +1,42 @@
 //! Implementation of process manager
 
 use super::{
-    elf::{elf_load_seg, load_icode_mapper, Elf32, PT_LOAD},
-    ipc::IpcInfo,
+    debug::{self, DebugState},
+    elf::{elf_load_seg, lazy_icode_mapper, lazy_load, Elf32, LazyPage, PF_W, PT_LOAD},
+    ipc::{IpcInfo, IpcStatus},
     schedule::schedule,
 };
 use crate::{
     error::MosError,
-    exception::{reset_kclock, Trapframe, TF_SIZE},
+    exception::{
+        fpu::{self, FpuContext},
+        reset_kclock,
+        watch::{self, Watchpoint},
+        Trapframe, TF_SIZE,
+    },
     mm::{
         layout::{
-            PteFlags, KSTACKTOP, NASID, PAGE_SIZE, PDSHIFT, PGSHIFT, UENVS, UPAGES, USTACKTOP,
-            UTOP, UVPT,
+            PteFlags, KSTACKTOP, NASID, PAGE_SIZE, PDSHIFT, PGSHIFT, UENVS, UPAGES, USTACKSIZE,
+            USTACKTOP, UTOP, UVPT,
         },
-        map::{PageDirectory, Pte},
+        map::{rss_reset, PageDirectory, Pte},
         page::{page_dec_ref, Page, PAGE_ALLOCATOR},
-        tlb_invalidate, PA, PPN, VA,
+        swap::swap_in,
+        tlb_invalidate,
+        vma::{Vma, VmaKind, VmaTree},
+        PA, PPN, VA,
     },
     mutex::Mutex,
-    platform::cp0reg::{STATUS_EXL, STATUS_IE, STATUS_IM7, STATUS_UM},
+    platform::cp0reg::{STATUS_CU1, STATUS_EXL, STATUS_IE, STATUS_IM2, STATUS_IM7, STATUS_UM},
     pm::ENV_MANAGER,
-    round,
+    round, round_down,
     syscall::pool_remove_user_on_exit,
 };
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
 use core::{
     arch::asm,
     cell::RefCell,
@@ -32,12 +45,15 @@ use core::{
 };
 use log::{info, warn};
 
-const NENV: usize = 1024;
+/// Maximum number of envs
+pub const NENV: usize = 1024;
 const NEW_ENV: Env = Env::new();
 static mut ENVS: Envs = Envs {
     env_array: [NEW_ENV; NENV],
 };
 static mut ASID_BITMAP: [usize; NASID / 32] = [0; NASID / 32];
+const NEW_ENV_EXT: EnvExt = EnvExt::new();
+static mut ENV_EXTS: [EnvExt; NENV] = [NEW_ENV_EXT; NENV];
 
 /// Implementation of env->env_status of original mos
 #[repr(u32)]
@@ -124,29 +140,61 @@ impl Env {
         }
     }
 
+    /// Get kernel-private state of this Env block
+    #[allow(clippy::mut_from_ref)]
+    pub fn ext(&self) -> &'static mut EnvExt {
+        unsafe { &mut (*addr_of_mut!(ENV_EXTS))[self.pos()] }
+    }
+
+    /// Bring the page at va into memory if it is swapped out or
+    /// not loaded from the ELF image yet
+    ///
+    /// # Returns
+    ///
+    /// Ok(true) if a page is mapped at va afterwards, MosError on failure
+    pub fn page_in(&mut self, va: VA) -> Result<bool, MosError> {
+        if self.pgdir().lookup(va).is_some() || swap_in(self.pgdir(), self.asid, va)? {
+            return Ok(true);
+        }
+        lazy_load(self, va)
+    }
+
     /// Get EnvTracker of this Env block, EnvTracker is created from env pos
     const fn tracker(&self) -> EnvTracker {
         EnvTracker::new(self.pos())
     }
 
     /// Load icode from binary to this env block
-    fn load_icode(&mut self, binary: &[u8]) {
+    /// Segments are only recorded here, pages are filled in on first access
+    fn load_icode(&mut self, binary: &'static [u8]) {
         if Elf32::is_elf32_format(binary) {
             let elf = Elf32::from_bytes(binary);
             let ehdr = elf.ehdr();
             for i in 0..ehdr.e_phnum as usize {
                 let phdr = elf.phdr(i);
-                if phdr.p_type == PT_LOAD as u32
-                    && elf_load_seg(
-                        phdr,
-                        &binary[phdr.p_offset as usize..],
-                        load_icode_mapper,
-                        self,
-                    )
-                    .is_err()
+                if phdr.p_type != PT_LOAD as u32 {
+                    continue;
+                }
+                if elf_load_seg(
+                    phdr,
+                    &binary[phdr.p_offset as usize..],
+                    lazy_icode_mapper,
+                    self,
+                )
+                .is_err()
                 {
                     panic!();
                 }
+                let start = round_down!(phdr.p_vaddr as usize, PAGE_SIZE);
+                let end = round!(phdr.p_vaddr as usize + phdr.p_memsz as usize, PAGE_SIZE);
+                let perm = if phdr.p_flags & PF_W != 0 {
+                    PteFlags::D
+                } else {
+                    PteFlags::empty()
+                };
+                self.ext()
+                    .vmas
+                    .map(Vma::new(start, end, perm, VmaKind::Elf));
             }
             self.tf.cp0_epc = ehdr.e_entry;
         } else {
@@ -159,14 +207,151 @@ impl Env {
         self.status == EnvStatus::Runnable
     }
 
+    /// Check if the env is stopped, by its parent, from the console or by its tracer
+    pub fn stopped(&self) -> bool {
+        self.ext().stopped || debug::is_stopped(self)
+    }
+
+    /// Make the env runnable once what it was blocked on happened, unless it is stopped
+    pub fn wake(&mut self) {
+        if self.status == EnvStatus::NotRunnable && !self.stopped() {
+            self.status = EnvStatus::Runnable;
+            ENV_MANAGER.lock().insert_to_end(self.id);
+        }
+    }
+
+    /// Stop the env until `cont`, whether it is running or blocked
+    pub fn stop(&mut self) {
+        self.ext().stopped = true;
+        if self.status == EnvStatus::Runnable {
+            self.status = EnvStatus::NotRunnable;
+            ENV_MANAGER.lock().remove_from_schedule(self.id);
+        }
+    }
+
+    /// Let the env go on after `stop`
+    ///
+    /// It stays blocked if it still waits for IPC or the end of a sleep. An env waiting
+    /// for console input looks for input again.
+    pub fn cont(&mut self) {
+        self.ext().stopped = false;
+        if self.ipc_info.recving == IpcStatus::NotReceiving && !self.ext().sleeping {
+            self.wake();
+        }
+    }
+
     /// Set user custom tlm mod exception handler from handler entry
     pub fn set_tlb_mod_entry(&mut self, entry: usize) {
         self.user_tlb_mod_entry = entry;
     }
 }
 
+/// Per-env kernel state which is not part of `Env`
+///
+/// `Env` is shared with user space through `UENVS`, so its layout must stay the same as
+/// in mos. Everything else the kernel keeps for an env lives here, indexed by env pos.
+#[derive(Debug)]
+pub struct EnvExt {
+    /// Pages of the ELF image not loaded yet, by virtual address
+    pub lazy_pages: BTreeMap<usize, LazyPage>,
+    /// Virtual memory areas of the address space
+    pub vmas: VmaTree,
+    /// Maximum size of the user stack
+    pub stack_max: usize,
+    /// FPU registers, saved while another env owns the FPU, None until the FPU is used
+    pub fpu: Option<Box<FpuContext>>,
+    /// User handler of breakpoint, reserved instruction, overflow and trap exceptions,
+    /// and of faults outside of the virtual memory areas, 0 for none
+    pub user_exception_entry: usize,
+    /// Emulate unaligned loads and stores instead of killing the env on them
+    pub emulate_unaligned: bool,
+    /// Data watchpoint loaded while the env runs
+    pub watch: Option<Watchpoint>,
+    /// Tracing by and of other envs
+    pub debug: DebugState,
+    /// Child made by `sys_exofork` and not started yet, by id: fork only duplicates valid
+    /// mappings, so no page of this env is swapped out meanwhile
+    pub forking: Option<usize>,
+    /// Blocked in `sys_sleep`
+    pub sleeping: bool,
+    /// Stopped by its parent or from the console, see `Env::stop`
+    pub stopped: bool,
+}
+
+/// Outcome of a fault below the user stack, see `EnvExt::grow_stack`
+#[derive(PartialEq, Eq, Debug)]
+pub enum StackGrowth {
+    /// The stack has been extended to cover the faulting address
+    Grown,
+    /// The faulting address is on the guard page below the stack limit
+    Overflow,
+    /// The faulting address has nothing to do with the stack
+    NotStack,
+}
+
+/// Accesses this far below the stack pointer still grow the stack
+const STACK_GROW_SLACK: usize = PAGE_SIZE;
+
+impl EnvExt {
+    /// Create an empty EnvExt
+    pub const fn new() -> Self {
+        Self {
+            lazy_pages: BTreeMap::new(),
+            vmas: VmaTree::new(),
+            stack_max: USTACKSIZE,
+            fpu: None,
+            user_exception_entry: 0,
+            emulate_unaligned: false,
+            watch: None,
+            debug: DebugState::new(),
+            forking: None,
+            sleeping: false,
+            stopped: false,
+        }
+    }
+
+    /// Lowest address the user stack may grow down to
+    pub const fn stack_limit(&self) -> usize {
+        USTACKTOP - self.stack_max
+    }
+
+    /// Bottom of the areas mapped contiguously below USTACKTOP
+    pub fn stack_bottom(&self) -> usize {
+        let mut bottom = USTACKTOP;
+        while let Some(vma) = self.vmas.find(bottom - 1) {
+            bottom = vma.start;
+        }
+        bottom
+    }
+
+    /// Extend the stack down to va after a fault outside of all areas
+    ///
+    /// The stack grows if va lies between the stack limit and the current bottom
+    /// of the stack, and not too far below sp. The page right below the limit is
+    /// a guard page which is never mapped.
+    pub fn grow_stack(&mut self, va: usize, sp: usize) -> StackGrowth {
+        let limit = self.stack_limit();
+        if (limit - PAGE_SIZE..limit).contains(&va) {
+            return StackGrowth::Overflow;
+        }
+        let bottom = self.stack_bottom();
+        if !(limit..bottom).contains(&va) || va + STACK_GROW_SLACK < sp {
+            return StackGrowth::NotStack;
+        }
+        self.vmas.cover(
+            round_down!(va, PAGE_SIZE),
+            bottom,
+            PteFlags::D,
+            VmaKind::Stack,
+        );
+        StackGrowth::Grown
+    }
+}
+
 /// Envs array, same as ENVS in mos
-#[repr(C, align(4096))]
+///
+/// Aligned to a pair of 64 KiB pages so `UENVS` can be mapped with large pages
+#[repr(C, align(0x20000))]
 pub struct Envs {
     env_array: [Env; NENV],
 }
@@ -243,6 +428,12 @@ impl EnvManager {
         self.cur.map(|tracker| env_at(tracker.pos))
     }
 
+    /// Acquire the Env block at position pos of ENVS if it is in use
+    pub fn env_in_use(&self, pos: usize) -> Option<&'static mut Env> {
+        let env = env_at(pos);
+        (env.status != EnvStatus::Free).then_some(env)
+    }
+
     /// Acquire a free Env block
     ///
     /// # Returns
@@ -315,6 +506,14 @@ impl EnvManager {
     pub fn alloc(&self, parent_id: usize) -> Result<&'static mut Env, MosError> {
         if let Ok(env) = self.get_free_env() {
             self.setup_vm(env)?;
+            *env.ext() = EnvExt::new();
+            // the stack starts with one page and grows on demand
+            env.ext().vmas.map(Vma::new(
+                USTACKTOP - PAGE_SIZE,
+                USTACKTOP,
+                PteFlags::D,
+                VmaKind::Stack,
+            ));
             env.user_tlb_mod_entry = 0;
             env.runs = 0;
             env.id = mkenvid(env);
@@ -322,8 +521,10 @@ impl EnvManager {
                 Ok(asid) => asid,
                 Err(_) => return Err(MosError::NoFreeEnv),
             };
+            rss_reset(env.asid);
             env.parent_id = parent_id;
-            env.tf.cp0_status = (STATUS_IM7 | STATUS_IE | STATUS_EXL | STATUS_UM) as u32;
+            env.tf.cp0_status =
+                (STATUS_IM7 | STATUS_IM2 | STATUS_IE | STATUS_EXL | STATUS_UM) as u32;
             env.tf.regs[29] = (USTACKTOP - size_of::<i32>() - size_of::<usize>()) as u32;
             Ok(env)
         } else {
@@ -332,7 +533,7 @@ impl EnvManager {
     }
 
     /// Create a Env from binary file, and set its priority
-    pub fn create(&self, binary: &[u8], priority: u32) -> &mut Env {
+    pub fn create(&self, binary: &'static [u8], priority: u32) -> &mut Env {
         let env = self.alloc(0).expect("failed to alloc env");
         env.priority = priority;
         env.status = EnvStatus::Runnable;
@@ -362,7 +563,7 @@ impl EnvManager {
             let pt = pa.kaddr().as_mut_ptr::<Pte>();
             for j in 0..PAGE_SIZE / size_of::<Pte>() {
                 let pte = unsafe { &mut *pt.add(j) };
-                if pte.is_valid() {
+                if pte.is_valid() || pte.is_swapped() {
                     env.pgdir()
                         .remove(env.asid, VA((i << PDSHIFT) + (j << PGSHIFT)));
                 }
@@ -372,6 +573,9 @@ impl EnvManager {
             tlb_invalidate(env.asid, VA(UVPT + (i << PGSHIFT)));
         }
         pool_remove_user_on_exit(env.id);
+        fpu::release(env);
+        debug::release(env);
+        *env.ext() = EnvExt::new();
         page_dec_ref(env.pgdir().page);
         asid_free(env.asid);
         tlb_invalidate(env.asid, VA(UVPT + (VA(UVPT).pdx() << PGSHIFT)));
@@ -428,17 +632,19 @@ impl EnvManager {
 
 /// Implementation of map_segment in mos
 /// Map [va, va+size) of virtual address space to physical [pa, pa+size) in the 'pgdir'. Use
-/// permission bits 'perm | PTE_V' for the entries.
+/// permission bits 'perm | PTE_V' for the entries, with large pages where alignment allows.
 fn map_segment(pgdir: PageDirectory, asid: usize, pa: PA, va: VA, size: usize, flags: PteFlags) {
     assert!(pa.0 % PAGE_SIZE == 0);
     assert!(va.0 % PAGE_SIZE == 0);
     assert!(size % PAGE_SIZE == 0);
 
-    for i in (0..size).step_by(PAGE_SIZE) {
-        pgdir
-            .insert(asid, Page::from(pa + i), va + i, flags | PteFlags::V)
-            .expect("failed on mapping");
-    }
+    let pages: Vec<Page> = (0..size)
+        .step_by(PAGE_SIZE)
+        .map(|i| Page::from(pa + i))
+        .collect();
+    pgdir
+        .insert_pages(asid, &pages, va, flags | PteFlags::V)
+        .expect("failed on mapping");
 }
 
 /// Acquire a new envid
@@ -497,6 +703,11 @@ pub fn env_run(env: &mut Env) -> ! {
     }
     env_man.cur = Some(env.tracker());
     env.runs += 1;
+    // the FPU may have been handed to another env since it last ran
+    if !fpu::owns(env.id) {
+        env.tf.cp0_status &= !(STATUS_CU1 as u32);
+    }
+    watch::install(env);
 
     env_man.cur_pgdir = env.pgdir();
     drop(env_man);
@@ -1,6 +1,7 @@
 // IPC struct definitions
 
-use crate::mm::VA;
+use super::Env;
+use crate::{error::MosError, mm::VA};
 
 /// IpcStatus enum for Ipc feature
 #[repr(u32)]
@@ -41,3 +42,21 @@ impl IpcInfo {
         }
     }
 }
+
+/// Send value, without a page, to env on behalf of env from, which may be the kernel
+///
+/// # Returns
+///
+/// `MosError::IpcNotRecv` if env is not receiving
+pub fn ipc_send_value(env: &mut Env, from: usize, value: u32) -> Result<(), MosError> {
+    let ipc_info = &mut env.ipc_info;
+    if ipc_info.recving == IpcStatus::NotReceiving {
+        return Err(MosError::IpcNotRecv);
+    }
+    ipc_info.recving = IpcStatus::NotReceiving;
+    ipc_info.value = value;
+    ipc_info.from = from;
+    ipc_info.perm = 0;
+    env.wake();
+    Ok(())
+}
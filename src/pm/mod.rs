@@ -1,5 +1,6 @@
 //! Process management module
 
+pub mod debug;
 mod elf;
 mod env;
 mod ipc;
@@ -11,8 +12,8 @@ use log::info;
 
 pub use env::env_destroy;
 use env::EnvManager;
-pub use env::EnvStatus;
-pub use ipc::IpcStatus;
+pub use env::{Env, EnvStatus, StackGrowth, NENV};
+pub use ipc::{ipc_send_value, IpcStatus};
 pub use schedule::schedule;
 
 lazy_static! {
@@ -30,12 +31,12 @@ pub fn init() {
 #[macro_export]
 macro_rules! env_create {
     ($name: ident, $path: expr) => {
-        let $name = include_bytes_align_as!(usize, $path);
+        let $name = include_bytes_align_as!($crate::macros::include_bytes::PageAlign, $path);
         $crate::pm::ENV_MANAGER.lock().create($name, 1);
     };
 
     ($name: ident, $path: expr, $priority: expr) => {
-        let $name = include_bytes_align_as!(usize, $path);
+        let $name = include_bytes_align_as!($crate::macros::include_bytes::PageAlign, $path);
         $crate::pm::ENV_MANAGER.lock().create($name, $priority);
     };
 }
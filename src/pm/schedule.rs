@@ -2,8 +2,11 @@
 
 use core::sync::atomic::{AtomicU32, Ordering};
 
+use crate::exception::{clock::has_timers, irq::wait_for_interrupt};
+use crate::mm::dedup;
 use crate::mutex::Mutex;
 use crate::pm::env::env_run;
+use crate::tty::has_input_waiters;
 
 use super::ENV_MANAGER;
 use log::trace;
@@ -13,6 +16,10 @@ use log::trace;
 #[no_mangle]
 pub extern "C" fn schedule(env_yield: bool) -> ! {
     static COUNT: AtomicU32 = AtomicU32::new(0);
+    if !env_yield {
+        // timer tick
+        dedup::tick();
+    }
     let mut env = ENV_MANAGER.lock().curenv();
     if env_yield
         || COUNT.load(Ordering::SeqCst) == 0
@@ -24,12 +31,18 @@ pub extern "C" fn schedule(env_yield: bool) -> ! {
                 ENV_MANAGER.lock().move_to_end(env);
             }
         }
-        if let Some(new_env) = ENV_MANAGER.lock().get_first() {
-            COUNT.store(new_env.priority, Ordering::SeqCst);
-            env = Some(new_env);
-        } else {
-            panic!("No runnable envs")
-        }
+        let new_env = loop {
+            if let Some(new_env) = ENV_MANAGER.lock().get_first() {
+                break new_env;
+            }
+            // only console input or a timer can make an env runnable again
+            if !has_input_waiters() && !has_timers() {
+                panic!("No runnable envs")
+            }
+            wait_for_interrupt();
+        };
+        COUNT.store(new_env.priority, Ordering::SeqCst);
+        env = Some(new_env);
     }
     COUNT.fetch_sub(1, Ordering::SeqCst);
     trace!(
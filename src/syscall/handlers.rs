@@ -2,23 +2,46 @@ use super::mempool::do_mempool_op;
 use crate::mutex::Mutex;
 use crate::{
     error::MosError,
-    exception::{Trapframe, TF_SIZE},
+    exception::{
+        clock::add_timer,
+        fpu,
+        irq::{irq_stats, IrqStats},
+        unaligned::{self, UnalignedStats},
+        watch::{self, watch_present, WatchKind, Watchpoint},
+        Trapframe, TF_SIZE,
+    },
     mm::{
         layout::{
             is_dev_va_range, is_illegal_user_va, is_illegal_user_va_range, PteFlags, KSTACKTOP,
-            UTOP,
+            PAGE_SIZE, UMMAP, USTACKSIZE_MAX, USTACKTOP, UTOP,
         },
-        page::{page_alloc, page_dealloc},
-        VA,
+        page::page_dealloc,
+        swap::{alloc_anon_page, swap_in_all},
+        dedup::{self, DedupStats},
+        mem_stats,
+        slab::{self, SlabStats},
+        vma::{protect_pages, Vma, VmaKind},
+        MemStats, VA,
     },
     platform::{
-        ioread_byte, ioread_half, ioread_word, iowrite_byte, iowrite_half, iowrite_word,
-        print_char, read_char,
+        ide, ioread_byte, ioread_half, ioread_word, iowrite_byte, iowrite_half, iowrite_word,
+        print_char,
+    },
+    pm::{
+        debug::{
+            self, DEBUG_CONTINUE, DEBUG_DETACH, DEBUG_GET_REGS, DEBUG_READ, DEBUG_SET_REGS,
+            DEBUG_STEP, DEBUG_WRITE,
+        },
+        env_destroy, schedule, Env, EnvStatus, IpcStatus, ENV_MANAGER,
     },
-    pm::{env_destroy, schedule, EnvStatus, IpcStatus, ENV_MANAGER},
+    round, round_down,
+    tty::{self, TtyMode, TTY_GET_MODE, TTY_SET_FOREGROUND, TTY_SET_MODE},
 };
 use alloc::string::String;
-use core::ptr;
+use core::{
+    mem::{align_of, size_of},
+    ptr,
+};
 use log::info;
 
 /// Print a character on screen.
@@ -47,6 +70,29 @@ pub fn sys_yield(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) ->
     schedule(true)
 }
 
+/// Block 'curenv' for 'us' microseconds.
+pub fn sys_sleep(us: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    env.ext().sleeping = true;
+    env.status = EnvStatus::NotRunnable;
+    ENV_MANAGER.lock().remove_from_schedule(env.id);
+    add_timer(us, wake_sleeper, env.id);
+    unsafe {
+        (*Trapframe::from_memory(VA(KSTACKTOP - TF_SIZE))).regs[2] = 0;
+    }
+    schedule(true)
+}
+
+/// Make the env of id runnable again once its `sys_sleep` is over
+fn wake_sleeper(id: usize) {
+    let Ok(env) = ENV_MANAGER.lock().env_from_id(id, false) else {
+        return;
+    };
+    if core::mem::take(&mut env.ext().sleeping) {
+        env.wake();
+    }
+}
+
 /// Destroy the current environment.
 pub fn sys_env_destroy(envid: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
     let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
@@ -76,6 +122,49 @@ pub fn sys_set_tlb_mod_entry(envid: u32, func: u32, _arg3: u32, _arg4: u32, _arg
     }
 }
 
+/// Register the user handler of breakpoint, reserved instruction, overflow and trap
+/// exceptions, and of faults outside of the virtual memory areas, of 'envid', 0 to kill
+/// the env on them instead.
+pub fn sys_set_exception_entry(envid: u32, func: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
+    match env {
+        Ok(env) => {
+            env.ext().user_exception_entry = func as usize;
+            0
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Set a data watchpoint on 'va' of 'envid', the caller or one of its children, for
+/// the accesses in 'kind', a combination of `WatchKind` bits. Hits are reported to the
+/// caller by IPC. A 'kind' of 0 removes the watchpoint of 'envid'.
+pub fn sys_set_watchpoint(envid: u32, va: u32, kind: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let Some(kind) = WatchKind::from_bits(kind) else {
+        return MosError::Inval.into();
+    };
+    if !kind.is_empty() && (is_illegal_user_va(va as usize) || !watch_present()) {
+        return MosError::Inval.into();
+    }
+    let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
+    match env {
+        Ok(env) => {
+            let curenv = ENV_MANAGER.lock().curenv().unwrap();
+            env.ext().watch = (!kind.is_empty()).then_some(Watchpoint {
+                va: va as usize,
+                kind,
+                owner: curenv.id,
+            });
+            // others load it when switched to
+            if env.id == curenv.id {
+                watch::install(env);
+            }
+            0
+        }
+        Err(err) => err.into(),
+    }
+}
+
 /// Allocate a physical page and map 'va' to it with 'perm' in the address space of 'envid'.
 /// If 'va' is already mapped, that original page is sliently unmapped.
 /// 'envid2env' should be used with 'checkperm' set, like in most syscalls, to ensure the target is
@@ -89,16 +178,17 @@ pub fn sys_mem_alloc(envid: u32, va: u32, perm: u32, _arg4: u32, _arg5: u32) ->
         return err.into();
     }
     let env = env.unwrap();
-    if let Some(page) = page_alloc(true) {
-        match env.pgdir().insert(
-            env.asid,
-            page,
-            VA(va as usize),
-            PteFlags::from_bits_truncate(perm as usize),
-        ) {
-            Ok(_) => 0,
+    let perm = PteFlags::from_user(perm);
+    if let Some(page) = alloc_anon_page(perm.contains(PteFlags::SHARED)) {
+        match env.pgdir().insert(env.asid, page, VA(va as usize), perm) {
+            Ok(_) => {
+                cover_heap_page(env, va);
+                0
+            }
             Err(err) => {
-                page_dealloc(page);
+                if !page.is_zero_page() {
+                    page_dealloc(page);
+                }
                 err.into()
             }
         }
@@ -123,14 +213,26 @@ pub fn sys_mem_map(srcid: u32, srcva: u32, dstid: u32, dstva: u32, perm: u32) ->
     }
     let srcenv = srcenv.unwrap();
     let dstenv = dstenv.unwrap();
+    if let Err(err) = page_in_for_map(srcenv, srcva, perm) {
+        return err.into();
+    }
+    let perm = PteFlags::from_user(perm);
+    // a copy-on-write page may be shared with other envs, e.g. merged by `dedup`, so
+    // the source gets a page of its own before it is mapped writable elsewhere
+    if perm.contains(PteFlags::D) {
+        if let Err(err) = srcenv.pgdir().resolve_cow(srcenv.asid, VA(srcva as usize)) {
+            return err.into();
+        }
+    }
     if let Some((_, page)) = srcenv.pgdir().lookup(VA(srcva as usize)) {
-        match dstenv.pgdir().insert(
-            dstenv.asid,
-            page,
-            VA(dstva as usize),
-            PteFlags::from_bits_truncate(perm as usize),
-        ) {
-            Ok(_) => 0,
+        match dstenv
+            .pgdir()
+            .insert(dstenv.asid, page, VA(dstva as usize), perm)
+        {
+            Ok(_) => {
+                cover_heap_page(dstenv, dstva);
+                0
+            }
             Err(err) => err.into(),
         }
     } else {
@@ -150,12 +252,27 @@ pub fn sys_mem_unmap(envid: u32, va: u32, _arg3: u32, _arg4: u32, _arg5: u32) ->
     }
     let env = env.unwrap();
     env.pgdir().remove(env.asid, VA(va as usize));
+    let va = round_down!(va as usize, PAGE_SIZE);
+    env.ext().lazy_pages.remove(&va);
+    if env
+        .ext()
+        .vmas
+        .find(va)
+        .is_some_and(|vma| vma.kind == VmaKind::Heap)
+    {
+        env.ext().vmas.unmap(va, va + PAGE_SIZE);
+    }
     0
 }
 
 /// Allocate a new env as a child of 'curenv'.
 pub unsafe fn sys_exofork(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
     let curenv = ENV_MANAGER.lock().curenv().unwrap();
+    // fork only duplicates valid mappings, bring swapped-out pages back first and keep
+    // them in until the child is started
+    if let Err(err) = swap_in_all(curenv.pgdir(), curenv.asid) {
+        return err.into();
+    }
     let env = ENV_MANAGER.lock().alloc(curenv.id);
     match env {
         Ok(env) => {
@@ -163,6 +280,15 @@ pub unsafe fn sys_exofork(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5:
             env.tf.regs[2] = 0;
             env.status = EnvStatus::NotRunnable;
             env.priority = curenv.priority;
+            // pages not loaded yet are invisible to fork, let the child load them itself
+            env.ext().lazy_pages = curenv.ext().lazy_pages.clone();
+            env.ext().vmas = curenv.ext().vmas.clone();
+            env.ext().stack_max = curenv.ext().stack_max;
+            env.ext().emulate_unaligned = curenv.ext().emulate_unaligned;
+            // the child starts from the FPU registers of the parent, if it has any
+            fpu::save(curenv);
+            env.ext().fpu = curenv.ext().fpu.clone();
+            curenv.ext().forking = Some(env.id);
             env.id as u32
         }
         Err(err) => err.into(),
@@ -170,6 +296,8 @@ pub unsafe fn sys_exofork(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5:
 }
 
 /// Set 'envid''s 'env_status' to 'status' and update 'env_sched_list'.
+///
+/// `ENV_NOT_RUNNABLE` stops the env, `ENV_RUNNABLE` lets it go on, see `Env::stop`.
 pub fn sys_set_env_status(envid: u32, status: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
     let status = match status {
         0 => EnvStatus::NotRunnable,
@@ -179,12 +307,14 @@ pub fn sys_set_env_status(envid: u32, status: u32, _arg3: u32, _arg4: u32, _arg5
     let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
     match env {
         Ok(env) => {
-            if status == EnvStatus::Runnable && env.status == EnvStatus::NotRunnable {
-                ENV_MANAGER.lock().insert_to_end(env.id);
-            } else if status == EnvStatus::NotRunnable && env.status == EnvStatus::Runnable {
-                ENV_MANAGER.lock().remove_from_schedule(env.id);
+            let curenv = ENV_MANAGER.lock().curenv().unwrap();
+            if status == EnvStatus::Runnable && curenv.ext().forking == Some(env.id) {
+                curenv.ext().forking = None;
+            }
+            match status {
+                EnvStatus::Runnable => env.cont(),
+                _ => env.stop(),
             }
-            env.status = status;
             0
         }
         Err(err) => err.into(),
@@ -247,13 +377,19 @@ pub fn sys_ipc_try_send(envid: u32, value: u32, srcva: u32, perm: u32, _arg5: u3
             if ipc_info.recving == IpcStatus::NotReceiving {
                 return MosError::IpcNotRecv.into();
             }
+            if srcva != 0 {
+                let curenv = ENV_MANAGER.lock().curenv().unwrap();
+                if let Err(err) = page_in_for_map(curenv, srcva, perm) {
+                    return err.into();
+                }
+            }
             ipc_info.recving = IpcStatus::NotReceiving;
             ipc_info.value = value;
             ipc_info.from = ENV_MANAGER.lock().curenv().unwrap().id;
             ipc_info.perm = perm as usize | PteFlags::V.bits();
+            let dstva = ipc_info.dstva;
 
-            env.status = EnvStatus::Runnable;
-            ENV_MANAGER.lock().insert_to_end(env.id);
+            env.wake();
 
             if srcva != 0 {
                 if let Some((_, page)) = ENV_MANAGER
@@ -263,14 +399,16 @@ pub fn sys_ipc_try_send(envid: u32, value: u32, srcva: u32, perm: u32, _arg5: u3
                     .pgdir()
                     .lookup(VA(srcva as usize))
                 {
-                    let dstva = ipc_info.dstva;
                     match env.pgdir().insert(
                         env.asid,
                         page,
                         dstva,
-                        PteFlags::from_bits_truncate(perm as usize),
+                        PteFlags::from_user(perm),
                     ) {
-                        Ok(_) => 0,
+                        Ok(_) => {
+                            cover_heap_page(env, dstva.0 as u32);
+                            0
+                        }
                         Err(err) => err.into(),
                     }
                 } else {
@@ -286,12 +424,19 @@ pub fn sys_ipc_try_send(envid: u32, value: u32, srcva: u32, perm: u32, _arg5: u3
 }
 
 /// Wait for a message (a value, together with a page if 'dstva' is not 0) from other envs.
-/// 'curenv' is blocked until a message is sent.
+/// 'curenv' is blocked until a message is sent, unless a stop of a tracee is pending.
 pub fn sys_ipc_recv(dstva: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
     if dstva != 0 && is_illegal_user_va(dstva as usize) {
         return MosError::Inval.into();
     }
     let env = ENV_MANAGER.lock().curenv().unwrap();
+    // stops of tracees reported while not receiving come first
+    if let Some((from, value)) = debug::take_report(env) {
+        env.ipc_info.value = value;
+        env.ipc_info.from = from;
+        env.ipc_info.perm = 0;
+        return 0;
+    }
     let ipc_info = &mut env.ipc_info;
     ipc_info.recving = IpcStatus::Receiving;
     ipc_info.dstva = VA(dstva as usize);
@@ -304,15 +449,26 @@ pub fn sys_ipc_recv(dstva: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32)
 }
 
 /// Gets char from console
+///
+/// The env is blocked until the TTY has input ready, it then issues the syscall again.
 pub fn sys_getchar(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
-    let mut c: char;
-    loop {
-        c = read_char();
-        if c != '\0' {
-            break;
-        }
+    if let Some(byte) = tty::read_byte() {
+        return byte as u32;
     }
-    c as u32
+    tty::wait_for_input(ENV_MANAGER.lock().curenv().unwrap());
+    unsafe {
+        (*Trapframe::from_memory(VA(KSTACKTOP - TF_SIZE))).cp0_epc -= size_of::<usize>() as u32;
+    }
+    schedule(true)
+}
+
+/// Gets char from console without blocking
+///
+/// # Returns
+///
+/// The char, or 0 if no input is available
+pub fn sys_try_getchar(_arg1: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    tty::read_byte().unwrap_or(0) as u32
 }
 
 /// Used to write data at 'va' with length 'len' to a device physical address
@@ -331,6 +487,12 @@ pub unsafe fn sys_write_dev(va: u32, pa: u32, len: u32, _arg4: u32, _arg5: u32)
     if !is_dev_va_range(pa as usize, len as usize) {
         return MosError::Inval.into();
     }
+    // the kernel does disk I/O too, the controller is only programmed by it
+    if ide::is_ide_reg(pa as usize) {
+        let src = core::slice::from_raw_parts(va as *const u8, len as usize);
+        ide::user_write(pa as usize, src);
+        return 0;
+    }
     match len {
         1 => iowrite_byte(pa as usize, *(va as *const u8)),
         2 => iowrite_half(pa as usize, *(va as *const u16)),
@@ -351,6 +513,11 @@ pub unsafe fn sys_read_dev(va: u32, pa: u32, len: u32, _arg4: u32, _arg5: u32) -
     if !is_dev_va_range(pa as usize, len as usize) {
         return MosError::Inval.into();
     }
+    if ide::is_ide_reg(pa as usize) {
+        let dst = core::slice::from_raw_parts_mut(va as *mut u8, len as usize);
+        ide::user_read(pa as usize, dst);
+        return 0;
+    }
     match len {
         1 => *(va as *mut u8) = ioread_byte(pa as usize),
         2 => *(va as *mut u16) = ioread_half(pa as usize),
@@ -391,3 +558,325 @@ pub unsafe fn sys_read_dev(va: u32, pa: u32, len: u32, _arg4: u32, _arg5: u32) -
 pub fn sys_mempool_op(op: u32, poolid: u32, va: u32, page_count: u32, _arg5: u32) -> u32 {
     do_mempool_op(op, poolid, va, page_count)
 }
+
+/// Bring the page at va of env into memory before mapping it elsewhere with perm
+///
+/// A zero page mapping that is shared writable first gets a private page, so that both
+/// sides see each other's writes
+fn page_in_for_map(env: &mut Env, va: u32, perm: u32) -> Result<(), MosError> {
+    let va = VA(va as usize);
+    env.page_in(va)?;
+    if PteFlags::from_bits_truncate(perm as usize).intersects(PteFlags::D | PteFlags::SHARED) {
+        env.pgdir().unshare_zero(env.asid, va)?;
+    }
+    Ok(())
+}
+
+/// Record a page mapped at va through a syscall as part of the heap of env,
+/// unless it already lies in another area
+fn cover_heap_page(env: &Env, va: u32) {
+    let va = round_down!(va as usize, PAGE_SIZE);
+    env.ext()
+        .vmas
+        .cover(va, va + PAGE_SIZE, PteFlags::D, VmaKind::Heap);
+}
+
+/// Round 'len' up to whole pages
+///
+/// # Returns
+///
+/// None if 'va' is not page aligned or 'len' is beyond the user address space
+fn page_aligned_len(va: u32, len: u32) -> Option<usize> {
+    if va as usize & (PAGE_SIZE - 1) != 0 || len as usize > UTOP {
+        return None;
+    }
+    Some(round!(len as usize, PAGE_SIZE))
+}
+
+/// Map 'len' bytes of zero-filled memory at 'va' in the address space of 'curenv'.
+/// If 'va' is 0, the kernel picks the address.
+/// Only `PTE_D` and `PTE_LIBRARY` of 'perm' are used, pages are allocated on first access.
+///
+/// # Returns
+///
+/// The address of the mapping, or an error if 'va' is not page aligned or the range is already
+/// in use.
+pub fn sys_mmap(va: u32, len: u32, perm: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let Some(len) = page_aligned_len(va, len).filter(|&len| len != 0) else {
+        return MosError::Inval.into();
+    };
+    let va = va as usize;
+    let perm = PteFlags::from_bits_truncate(perm as usize) & (PteFlags::D | PteFlags::SHARED);
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    // keep clear of the stack reserve and its guard page
+    let guard = env.ext().stack_limit() - PAGE_SIZE;
+    let vmas = &mut env.ext().vmas;
+    let start = if va == 0 {
+        match vmas.find_free(len, UMMAP, guard) {
+            Some(start) => start,
+            None => return MosError::NoMem.into(),
+        }
+    } else {
+        if is_illegal_user_va_range(va, len) || va + len > guard || vmas.overlaps(va, va + len) {
+            return MosError::Inval.into();
+        }
+        va
+    };
+    vmas.map(Vma::new(start, start + len, perm, VmaKind::Anonymous));
+    start as u32
+}
+
+/// Unmap [va, va + len) in the address space of 'curenv', whatever kind of area it belongs to.
+pub fn sys_munmap(va: u32, len: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let Some(len) = page_aligned_len(va, len) else {
+        return MosError::Inval.into();
+    };
+    let va = va as usize;
+    if is_illegal_user_va_range(va, len) {
+        return MosError::Inval.into();
+    }
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    for page_va in (va..va + len).step_by(PAGE_SIZE) {
+        env.pgdir().remove(env.asid, VA(page_va));
+    }
+    env.ext().lazy_pages.retain(|&lazy_va, _| !(va..va + len).contains(&lazy_va));
+    env.ext().vmas.unmap(va, va + len);
+    0
+}
+
+/// Change the permission of [va, va + len) in the address space of 'curenv'.
+/// Only `PTE_D` and `PTE_LIBRARY` of 'perm' are used, copy-on-write pages stay read-only.
+///
+/// # Returns
+///
+/// 0 on success, or an error if part of the range is not mapped.
+pub fn sys_mprotect(va: u32, len: u32, perm: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let Some(len) = page_aligned_len(va, len) else {
+        return MosError::Inval.into();
+    };
+    let va = va as usize;
+    if is_illegal_user_va_range(va, len) {
+        return MosError::Inval.into();
+    }
+    let perm = PteFlags::from_bits_truncate(perm as usize) & (PteFlags::D | PteFlags::SHARED);
+    let env = ENV_MANAGER.lock().curenv().unwrap();
+    if let Err(err) = env.ext().vmas.protect(va, va + len, perm) {
+        return err.into();
+    }
+    env.ext()
+        .lazy_pages
+        .range_mut(va..va + len)
+        .for_each(|(_, lazy)| lazy.set_perm(perm));
+    match protect_pages(env.pgdir(), env.asid, va, va + len, perm) {
+        Ok(()) => 0,
+        Err(err) => err.into(),
+    }
+}
+
+/// Set the maximum stack size of 'envid' to 'size' bytes, rounded up to whole pages.
+///
+/// # Returns
+///
+/// 0 on success, or an error if 'size' is out of range, smaller than the stack in use, or the
+/// new stack reserve and its guard page would overlap other mappings.
+pub fn sys_set_stack_size(envid: u32, size: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    if size == 0 || size as usize > USTACKSIZE_MAX {
+        return MosError::Inval.into();
+    }
+    let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
+    if let Err(err) = env {
+        return err.into();
+    }
+    let ext = env.unwrap().ext();
+    let size = round!(size as usize, PAGE_SIZE);
+    let limit = USTACKTOP - size;
+    let bottom = ext.stack_bottom();
+    if limit > bottom || ext.vmas.overlaps(limit - PAGE_SIZE, bottom) {
+        return MosError::Inval.into();
+    }
+    ext.stack_max = size;
+    0
+}
+
+/// Write memory statistics of the system and of 'envid' to the `MemStats` at 'buf', and
+/// those of up to 'nslabs' slab caches to the `SlabStats` array at 'slabs' unless it is 0.
+///
+/// Returns the number of slab caches.
+pub unsafe fn sys_mem_stats(envid: u32, buf: u32, slabs: u32, nslabs: u32, _arg5: u32) -> u32 {
+    let caches = slab::stats();
+    let nslabs = (nslabs as usize).min(caches.len());
+    if buf as usize & (align_of::<MemStats>() - 1) != 0
+        || is_illegal_user_va_range(buf as usize, size_of::<MemStats>())
+        || slabs != 0
+            && (slabs as usize & (align_of::<SlabStats>() - 1) != 0
+                || is_illegal_user_va_range(slabs as usize, nslabs * size_of::<SlabStats>()))
+    {
+        return MosError::Inval.into();
+    }
+    match ENV_MANAGER.lock().env_from_id(envid as usize, true) {
+        Ok(env) => {
+            *(buf as *mut MemStats) = mem_stats(env.asid);
+            if slabs != 0 {
+                ptr::copy_nonoverlapping(caches.as_ptr(), slabs as *mut SlabStats, nslabs);
+            }
+            caches.len() as u32
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Write the interrupt counts, spurious and unhandled ones included, to the `IrqStats`
+/// at 'buf'.
+pub unsafe fn sys_irq_stats(buf: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    if buf as usize & (align_of::<IrqStats>() - 1) != 0
+        || is_illegal_user_va_range(buf as usize, size_of::<IrqStats>())
+    {
+        return MosError::Inval.into();
+    }
+    *(buf as *mut IrqStats) = irq_stats();
+    0
+}
+
+/// Control page deduplication: 'op' 0 stops the scanner, 1 starts it, and 2 writes its
+/// statistics to the `DedupStats` at 'buf'.
+///
+/// The scanner merges pages of all envs, and the time of a write to a merged page tells
+/// whether some other env holds the same content, so only envs without a parent may
+/// start or stop it.
+pub unsafe fn sys_dedup_op(op: u32, buf: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    match op {
+        0 | 1 => {
+            if ENV_MANAGER.lock().curenv().unwrap().parent_id != 0 {
+                return MosError::BadEnv.into();
+            }
+            dedup::set_enabled(op == 1);
+            0
+        }
+        2 => {
+            if buf as usize & (align_of::<DedupStats>() - 1) != 0
+                || is_illegal_user_va_range(buf as usize, size_of::<DedupStats>())
+            {
+                return MosError::Inval.into();
+            }
+            *(buf as *mut DedupStats) = dedup::stats();
+            0
+        }
+        _ => MosError::Inval.into(),
+    }
+}
+
+/// Console TTY operations
+///
+/// - `TTY_GET_MODE`: returns the mode
+/// - `TTY_SET_MODE`: sets the mode to arg, a combination of `TtyMode` bits
+/// - `TTY_SET_FOREGROUND`: makes env arg, the caller or one of its children, the target
+///   of ^C and ^Z
+pub fn sys_tty_op(op: u32, arg: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    match op {
+        TTY_GET_MODE => tty::mode().bits(),
+        TTY_SET_MODE => match TtyMode::from_bits(arg) {
+            Some(mode) => {
+                tty::set_mode(mode);
+                0
+            }
+            None => MosError::Inval.into(),
+        },
+        TTY_SET_FOREGROUND => match ENV_MANAGER.lock().env_from_id(arg as usize, true) {
+            Ok(env) => {
+                tty::set_foreground(env.id);
+                0
+            }
+            Err(err) => err.into(),
+        },
+        _ => MosError::Inval.into(),
+    }
+}
+
+/// Unaligned access emulation operations
+///
+/// - 0 / 1: stop / start emulating unaligned loads and stores of the caller, its
+///   children created afterwards inherit the setting
+/// - 2: copy the `UnalignedStats` of all envs to 'buf'
+pub unsafe fn sys_unaligned_op(op: u32, buf: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    match op {
+        0 | 1 => {
+            ENV_MANAGER.lock().curenv().unwrap().ext().emulate_unaligned = op == 1;
+            0
+        }
+        2 => {
+            if buf as usize & (align_of::<UnalignedStats>() - 1) != 0
+                || is_illegal_user_va_range(buf as usize, size_of::<UnalignedStats>())
+            {
+                return MosError::Inval.into();
+            }
+            *(buf as *mut UnalignedStats) = unaligned::stats();
+            0
+        }
+        _ => MosError::Inval.into(),
+    }
+}
+
+/// Attach the caller as the debugger of 'envid', one of its children. The env is stopped
+/// if runnable, and its stops are reported to the caller by IPC from then on.
+pub fn sys_debug_attach(envid: u32, _arg2: u32, _arg3: u32, _arg4: u32, _arg5: u32) -> u32 {
+    let env = ENV_MANAGER.lock().env_from_id(envid as usize, true);
+    match env {
+        Ok(env) => match debug::attach(ENV_MANAGER.lock().curenv().unwrap(), env) {
+            Ok(()) => 0,
+            Err(err) => err.into(),
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/// Operate on 'envid', traced by the caller, with 'op' one of the `DEBUG_*` ops.
+/// Registers are copied from or to 'buf', as a `Trapframe`. Memory is copied between
+/// 'len' bytes at 'arg' in 'envid' and 'buf'. To continue or step, 'arg' is not 0 to
+/// deliver the exception 'envid' stopped on.
+pub unsafe fn sys_debug_op(envid: u32, op: u32, arg: u32, buf: u32, len: u32) -> u32 {
+    let env = ENV_MANAGER.lock().env_from_id(envid as usize, false);
+    let env = match env {
+        Ok(env) if debug::is_traced_by(env, ENV_MANAGER.lock().curenv().unwrap().id) => env,
+        Ok(_) => return MosError::BadEnv.into(),
+        Err(err) => return err.into(),
+    };
+    let (buf, len) = (buf as usize, len as usize);
+    let result = match op {
+        DEBUG_GET_REGS | DEBUG_SET_REGS => {
+            if is_illegal_user_va_range(buf, TF_SIZE) {
+                return MosError::Inval.into();
+            }
+            let buf = buf as *mut Trapframe;
+            if op == DEBUG_GET_REGS {
+                buf.write_unaligned(env.tf);
+            } else {
+                // the env stays in user mode
+                let status = env.tf.cp0_status;
+                env.tf = buf.read_unaligned();
+                env.tf.cp0_status = status;
+            }
+            Ok(())
+        }
+        DEBUG_READ | DEBUG_WRITE => {
+            if is_illegal_user_va_range(buf, len) || is_illegal_user_va_range(arg as usize, len) {
+                return MosError::Inval.into();
+            }
+            let buf = core::slice::from_raw_parts_mut(buf as *mut u8, len);
+            if op == DEBUG_READ {
+                debug::read_memory(env, arg as usize, buf)
+            } else {
+                debug::write_memory(env, arg as usize, buf)
+            }
+        }
+        DEBUG_CONTINUE | DEBUG_STEP => debug::resume(env, op == DEBUG_STEP, arg != 0),
+        DEBUG_DETACH => {
+            debug::detach(env);
+            Ok(())
+        }
+        _ => Err(MosError::Inval),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => err.into(),
+    }
+}
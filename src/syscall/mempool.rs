@@ -3,7 +3,12 @@ use crate::{
     error::MosError,
     mm::{
         layout::{is_illegal_user_va_range, PteFlags, PAGE_SIZE},
-        page::{page_alloc, page_inc_ref, try_recycle, Page},
+        page::{
+            page_alloc_contiguous, page_dealloc_contiguous, page_dec_ref, page_inc_ref, page_split,
+            try_recycle, Page,
+        },
+        slab::{SlabBox, TypedCache},
+        vma::{Vma, VmaKind},
         VA,
     },
     mutex::FakeLock,
@@ -19,17 +24,20 @@ lazy_static! {
         current_id: 1,
         pools: BTreeMap::new(),
     });
+    static ref MEMPOOL_CACHE: TypedCache<MemPool> = TypedCache::new("mempool");
 }
 
 struct MemPoolManager {
     current_id: u32,
-    pools: BTreeMap<u32, MemPool>,
+    pools: BTreeMap<u32, SlabBox<MemPool>>,
 }
 
 struct MemPool {
     id: u32,
     page_count: u32,
     pages: Vec<Page>,
+    /// Contiguous blocks backing pages, with their page count
+    blocks: Vec<(Page, usize)>,
     users: BTreeMap<usize, VA>,
     write_mutex: AtomicBool,
     write_lock: bool,
@@ -84,10 +92,11 @@ pub fn do_mempool_op(op: u32, poolid: u32, va: u32, page_count: u32) -> u32 {
 
 fn mempool_create(page_count: u32) -> u32 {
     let id = POOL_MANAGER.lock().current_id;
-    let mut pool = MemPool {
+    let Some(mut pool) = MEMPOOL_CACHE.alloc(MemPool {
         id,
         page_count,
         pages: Vec::new(),
+        blocks: Vec::new(),
         users: BTreeMap::new(),
         write_mutex: AtomicBool::new(false),
         write_lock: false,
@@ -95,14 +104,22 @@ fn mempool_create(page_count: u32) -> u32 {
         read_mutex: AtomicBool::new(false),
         read_lock: 0,
         readers: Vec::new(),
+    }) else {
+        return MosError::NoMem.into();
     };
-    for _ in 0..page_count {
-        let Some(page) = page_alloc(true) else {
-            pool.pages.iter().for_each(|&page| try_recycle(page));
+    let mut left = page_count as usize;
+    while left > 0 {
+        let Some((block, size)) = alloc_pool_block(left) else {
+            free_pool_blocks(&pool.blocks);
             return MosError::NoMem.into();
         };
-        page_inc_ref(page);
-        pool.pages.push(page);
+        for i in 0..size {
+            let page = Page::new(block.ppn() + i);
+            page_inc_ref(page);
+            pool.pages.push(page);
+        }
+        pool.blocks.push((block, size));
+        left -= size;
     }
     POOL_MANAGER.lock().pools.insert(id, pool);
     POOL_MANAGER.lock().current_id += 1;
@@ -119,6 +136,13 @@ fn mempool_join(poolid: u32, va: u32, page_count: u32) -> u32 {
             return MosError::Inval.into();
         }
         pool.users.insert(env.id, VA(va as usize));
+        // pool pages are only mapped while a lock is held, other accesses are faults
+        env.ext().vmas.map(Vma::new(
+            va as usize,
+            va as usize + page_count as usize * PAGE_SIZE,
+            PteFlags::D,
+            VmaKind::Mempool,
+        ));
         0
     } else {
         MosError::NotFound.into()
@@ -136,7 +160,10 @@ fn mempool_leave(poolid: u32) -> u32 {
         {
             return MosError::PoolNotReleased.into();
         }
-        pool.users.remove(&env.id);
+        let va = pool.users.remove(&env.id).unwrap();
+        env.ext()
+            .vmas
+            .unmap(va.0, va.0 + pool.page_count as usize * PAGE_SIZE);
         // don't free the pool if the last user gracefully leaves
         0
     } else {
@@ -188,22 +215,8 @@ fn mempool_acquire_write_lock(poolid: u32) -> u32 {
         let asid = env.asid;
         let va = pool.users.get(&env.id).unwrap();
         let flags = PteFlags::V | PteFlags::D;
-        if (va.0..va.0 + pool.page_count as usize * PAGE_SIZE)
-            .step_by(PAGE_SIZE)
-            .enumerate()
-            .try_fold((), |(), (i, va)| {
-                let va = VA(va);
-                let page = pool.pages[i];
-                if env.pgdir().insert(asid, page, va, flags).is_err() {
-                    warn!("mempool_acquire_write_lock: insert failed");
-                    (0..i).for_each(|j| env.pgdir().remove(asid, VA(va.0 + j * PAGE_SIZE)));
-                    Err(())
-                } else {
-                    Ok(())
-                }
-            })
-            .is_err()
-        {
+        if env.pgdir().insert_pages(asid, &pool.pages, *va, flags).is_err() {
+            warn!("mempool_acquire_write_lock: insert failed");
             pool.write_lock = false;
             pool.writer = 0;
             pool.read_mutex.store(false, Ordering::Release);
@@ -272,22 +285,8 @@ fn mempool_acquire_read_lock(poolid: u32) -> u32 {
         let asid = env.asid;
         let va = pool.users.get(&env.id).unwrap();
         let flags = PteFlags::V;
-        if (va.0..va.0 + pool.page_count as usize * PAGE_SIZE)
-            .step_by(PAGE_SIZE)
-            .enumerate()
-            .try_fold((), |(), (i, va)| {
-                let va = VA(va);
-                let page = pool.pages[i];
-                if env.pgdir().insert(asid, page, va, flags).is_err() {
-                    warn!("mempool_acquire_read_lock: insert failed");
-                    (0..i).for_each(|j| env.pgdir().remove(asid, VA(va.0 + j * PAGE_SIZE)));
-                    Err(())
-                } else {
-                    Ok(())
-                }
-            })
-            .is_err()
-        {
+        if env.pgdir().insert_pages(asid, &pool.pages, *va, flags).is_err() {
+            warn!("mempool_acquire_read_lock: insert failed");
             pool.read_lock -= 1;
             pool.readers.retain(|&reader| reader != env.id);
             pool.read_mutex.store(false, Ordering::Release);
@@ -336,10 +335,38 @@ fn free_pool(poolid: u32) {
     assert!(pool_man.pools.contains_key(&poolid));
     let pool = pool_man.pools.get_mut(&poolid).unwrap();
     assert!(pool.users.is_empty());
-    pool.pages.iter().for_each(|&page| try_recycle(page));
+    free_pool_blocks(&pool.blocks);
     pool_man.pools.remove(&poolid);
 }
 
+/// Allocate a cleared block of at most left pool pages
+///
+/// Blocks as large as a 16 MiB, 1 MiB or 64 KiB page are tried first, so that
+/// the pool can be mapped with large pages.
+fn alloc_pool_block(left: usize) -> Option<(Page, usize)> {
+    [0x1000, 0x100, 0x10, 1]
+        .into_iter()
+        .filter(|&size| size <= left)
+        .find_map(|size| page_alloc_contiguous(true, size).map(|block| (block, size)))
+}
+
+/// Release the page blocks of a pool
+///
+/// A block with pages still mapped elsewhere, by `sys_mem_map` for instance, is not
+/// freed whole: each of its pages goes back once its last reference is dropped.
+fn free_pool_blocks(blocks: &[(Page, usize)]) {
+    for &(block, size) in blocks {
+        let pages = (0..size).map(|i| Page::new(block.ppn() + i));
+        if pages.clone().all(|page| page.ref_count() == 1) {
+            pages.for_each(page_dec_ref);
+            page_dealloc_contiguous(block, size);
+        } else {
+            page_split(size);
+            pages.for_each(try_recycle);
+        }
+    }
+}
+
 /// Remove the user from all memory pools on exit, in case the user exits unexpectedly and causes memory leaks or deadlocks.
 pub fn pool_remove_user_on_exit(env_id: usize) {
     for pool in POOL_MANAGER.lock().pools.values_mut() {
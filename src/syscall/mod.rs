@@ -30,7 +30,22 @@ enum Syscall {
     WriteDev = 16,
     ReadDev = 17,
     MempoolOp = 18,
-    Unhandled = 19,
+    Mmap = 19,
+    Munmap = 20,
+    Mprotect = 21,
+    SetStackSize = 22,
+    MemStats = 23,
+    DedupOp = 24,
+    TryGetchar = 25,
+    TtyOp = 26,
+    SetExceptionEntry = 27,
+    UnalignedOp = 28,
+    SetWatchpoint = 29,
+    DebugAttach = 30,
+    DebugOp = 31,
+    Sleep = 32,
+    IrqStats = 33,
+    Unhandled = 34,
 }
 
 impl Syscall {
@@ -55,6 +70,21 @@ impl Syscall {
             16 => Self::WriteDev,
             17 => Self::ReadDev,
             18 => Self::MempoolOp,
+            19 => Self::Mmap,
+            20 => Self::Munmap,
+            21 => Self::Mprotect,
+            22 => Self::SetStackSize,
+            23 => Self::MemStats,
+            24 => Self::DedupOp,
+            25 => Self::TryGetchar,
+            26 => Self::TtyOp,
+            27 => Self::SetExceptionEntry,
+            28 => Self::UnalignedOp,
+            29 => Self::SetWatchpoint,
+            30 => Self::DebugAttach,
+            31 => Self::DebugOp,
+            32 => Self::Sleep,
+            33 => Self::IrqStats,
             _ => Self::Unhandled,
         }
     }
@@ -62,7 +92,7 @@ impl Syscall {
 
 type SyscallHandler = unsafe fn(u32, u32, u32, u32, u32) -> u32;
 
-const SYSCALL_NUM: usize = 19;
+const SYSCALL_NUM: usize = 34;
 
 const HANDLER_TABLE: [SyscallHandler; SYSCALL_NUM] = [
     /* 00 */ handlers::sys_putchar,
@@ -84,6 +114,21 @@ const HANDLER_TABLE: [SyscallHandler; SYSCALL_NUM] = [
     /* 16 */ handlers::sys_write_dev,
     /* 17 */ handlers::sys_read_dev,
     /* 18 */ handlers::sys_mempool_op,
+    /* 19 */ handlers::sys_mmap,
+    /* 20 */ handlers::sys_munmap,
+    /* 21 */ handlers::sys_mprotect,
+    /* 22 */ handlers::sys_set_stack_size,
+    /* 23 */ handlers::sys_mem_stats,
+    /* 24 */ handlers::sys_dedup_op,
+    /* 25 */ handlers::sys_try_getchar,
+    /* 26 */ handlers::sys_tty_op,
+    /* 27 */ handlers::sys_set_exception_entry,
+    /* 28 */ handlers::sys_unaligned_op,
+    /* 29 */ handlers::sys_set_watchpoint,
+    /* 30 */ handlers::sys_debug_attach,
+    /* 31 */ handlers::sys_debug_op,
+    /* 32 */ handlers::sys_sleep,
+    /* 33 */ handlers::sys_irq_stats,
 ];
 
 /// Implementation of do_syscall in original mos
@@ -0,0 +1,302 @@
+//! Terminal line discipline on top of the UART driver.
+//!
+//! Bytes received on the serial line go through the TTY before envs read them. In raw
+//! mode they are handed over as they come. In cooked mode the TTY edits a line on its
+//! own and only hands it over once Enter is hit:
+//!
+//! - backspace or DEL erases a char, ^U the whole line and ^W the word before the cursor
+//! - ^D hands over the line without a newline, on an empty line it reads as [`TTY_EOF`]
+//! - ^C destroys the foreground env and ^Z stops it, even while it waits for input,
+//!   until it is made runnable again
+//!
+//! Echo can be turned on in either mode. The TTY starts raw without echo, the way the
+//! console always behaved, and envs switch modes with `sys_tty_op`.
+
+use crate::{
+    exception::irq::register_irq,
+    mutex::{FakeLock, Mutex},
+    platform::{malta::SERIAL_IRQ, print_char, put_byte, read_char, uart_init, uart_intr},
+    pm::{env_destroy, schedule, Env, EnvStatus, ENV_MANAGER},
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use lazy_static::lazy_static;
+use log::info;
+
+bitflags! {
+    /// Modes of the TTY
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct TtyMode: u32 {
+        /// Cooked mode: line editing and signal keys
+        const CANON = 1 << 0;
+        /// Echo input back to the console
+        const ECHO = 1 << 1;
+    }
+}
+
+/// Read in cooked mode at end of input
+pub const TTY_EOF: u8 = 0x04;
+
+/// `sys_tty_op`: acquire the mode
+pub const TTY_GET_MODE: u32 = 0;
+/// `sys_tty_op`: set the mode
+pub const TTY_SET_MODE: u32 = 1;
+/// `sys_tty_op`: set the foreground env, the target of ^C and ^Z
+pub const TTY_SET_FOREGROUND: u32 = 2;
+
+/// Longest line edited in cooked mode
+const LINE_MAX: usize = 256;
+/// Most bytes waiting to be read, later ones are dropped
+const INPUT_MAX: usize = 1024;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const CTRL_Z: u8 = 0x1a;
+const DEL: u8 = 0x7f;
+
+/// Action of a signal key on the foreground env
+#[derive(Clone, Copy, Debug)]
+enum Signal {
+    Interrupt,
+    Stop,
+}
+
+/// TTY state
+struct Tty {
+    mode: TtyMode,
+    /// Line being edited in cooked mode
+    line: Vec<u8>,
+    /// Bytes ready to be read
+    input: VecDeque<u8>,
+    /// Env receiving signal keys, by id
+    foreground: Option<usize>,
+    /// Envs blocked until input is ready, by id
+    waiters: Vec<usize>,
+}
+
+impl Tty {
+    const fn new() -> Self {
+        Self {
+            mode: TtyMode::empty(),
+            line: Vec::new(),
+            input: VecDeque::new(),
+            foreground: None,
+            waiters: Vec::new(),
+        }
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        if self.mode.contains(TtyMode::ECHO) {
+            for &byte in bytes {
+                match byte {
+                    b'\n' => print_char('\n'),
+                    _ => put_byte(byte),
+                }
+            }
+        }
+    }
+
+    fn push_input(&mut self, bytes: &[u8]) {
+        let room = INPUT_MAX - self.input.len();
+        self.input.extend(bytes.iter().take(room));
+    }
+
+    /// Erase the last char of the line
+    fn erase(&mut self) -> bool {
+        if self.line.pop().is_none() {
+            return false;
+        }
+        self.echo(b"\x08 \x08");
+        true
+    }
+
+    /// Hand the edited line over to readers
+    fn finish_line(&mut self) {
+        let line = core::mem::take(&mut self.line);
+        self.push_input(&line);
+    }
+
+    /// Process a received byte
+    ///
+    /// # Returns
+    ///
+    /// The signal raised by the byte, if any
+    fn receive(&mut self, byte: u8) -> Option<Signal> {
+        if !self.mode.contains(TtyMode::CANON) {
+            self.echo(&[byte]);
+            self.push_input(&[byte]);
+            return None;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                self.echo(b"\n");
+                self.line.push(b'\n');
+                self.finish_line();
+            }
+            BACKSPACE | DEL => {
+                self.erase();
+            }
+            CTRL_U => while self.erase() {},
+            CTRL_W => {
+                while self.line.last() == Some(&b' ') && self.erase() {}
+                while self.line.last().is_some_and(|&c| c != b' ') && self.erase() {}
+            }
+            CTRL_D => {
+                if self.line.is_empty() {
+                    self.push_input(&[TTY_EOF]);
+                } else {
+                    self.finish_line();
+                }
+            }
+            CTRL_C | CTRL_Z => {
+                self.echo(if byte == CTRL_C { b"^C\n" } else { b"^Z\n" });
+                self.line.clear();
+                return Some(if byte == CTRL_C {
+                    Signal::Interrupt
+                } else {
+                    Signal::Stop
+                });
+            }
+            _ => {
+                if self.line.len() < LINE_MAX - 1 {
+                    self.echo(&[byte]);
+                    self.line.push(byte);
+                }
+            }
+        }
+        None
+    }
+}
+
+lazy_static! {
+    static ref TTY: FakeLock<Tty> = FakeLock::new(Tty::new());
+}
+
+/// Switch the console to interrupt driven input through the TTY
+pub fn init() {
+    uart_init();
+    register_irq(SERIAL_IRQ, tty_intr).expect("tty: serial IRQ is taken");
+}
+
+/// Handler of the serial IRQ line
+fn tty_intr(_irq: usize) {
+    if uart_intr() {
+        receive_pending();
+    }
+}
+
+/// Run the bytes received by the UART through the line discipline, wake readers if
+/// input got ready and act on signal keys
+///
+/// Acting on a signal key may reschedule, in which case this never returns.
+fn receive_pending() {
+    let mut tty = TTY.lock();
+    let mut signal = None;
+    loop {
+        let c = read_char();
+        if c == '\0' {
+            break;
+        }
+        signal = tty.receive(c as u8).or(signal);
+    }
+    if !tty.input.is_empty() {
+        wake_waiters(core::mem::take(&mut tty.waiters));
+    }
+    let foreground = tty.foreground;
+    drop(tty);
+    if let (Some(signal), Some(id)) = (signal, foreground) {
+        raise(signal, id);
+    }
+}
+
+/// Make every env in waiters runnable again, but stopped ones
+///
+/// A stopped env looks for input again once it goes on.
+fn wake_waiters(waiters: Vec<usize>) {
+    for id in waiters {
+        // the env may have been destroyed meanwhile
+        if let Ok(env) = ENV_MANAGER.lock().env_from_id(id, false) {
+            env.wake();
+        }
+    }
+}
+
+/// Act on a signal key for the env of id
+fn raise(signal: Signal, id: usize) {
+    let Ok(env) = ENV_MANAGER.lock().env_from_id(id, false) else {
+        // the foreground env is gone
+        TTY.lock().foreground = None;
+        return;
+    };
+    match signal {
+        Signal::Interrupt => {
+            info!("{:08x}: interrupted from the console", env.id);
+            TTY.lock().foreground = None;
+            env_destroy(env);
+        }
+        Signal::Stop => {
+            if env.ext().stopped {
+                return;
+            }
+            info!("{:08x}: stopped from the console", env.id);
+            env.stop();
+            let current = ENV_MANAGER.lock().curenv().map(|cur| cur.id);
+            if current == Some(env.id) {
+                schedule(true);
+            }
+        }
+    }
+}
+
+/// Read a byte of input without blocking
+///
+/// # Returns
+///
+/// The byte, or None if no input is ready
+pub fn read_byte() -> Option<u8> {
+    // interrupts are only taken in user mode, bytes may still wait in the UART
+    receive_pending();
+    TTY.lock().input.pop_front()
+}
+
+/// Block env until input is ready
+///
+/// The caller must reschedule afterwards.
+pub fn wait_for_input(env: &mut Env) {
+    TTY.lock().waiters.push(env.id);
+    env.status = EnvStatus::NotRunnable;
+    ENV_MANAGER.lock().remove_from_schedule(env.id);
+}
+
+/// Check if any env waits for input
+pub fn has_input_waiters() -> bool {
+    !TTY.lock().waiters.is_empty()
+}
+
+/// Acquire the mode of the TTY
+pub fn mode() -> TtyMode {
+    TTY.lock().mode
+}
+
+/// Set the mode of the TTY
+///
+/// A line being edited is handed over to readers when leaving cooked mode.
+pub fn set_mode(mode: TtyMode) {
+    let mut tty = TTY.lock();
+    if !mode.contains(TtyMode::CANON) {
+        tty.finish_line();
+        if !tty.input.is_empty() {
+            wake_waiters(core::mem::take(&mut tty.waiters));
+        }
+    }
+    tty.mode = mode;
+}
+
+/// Set the env receiving signal keys, by id
+///
+/// It stays the foreground env until it is destroyed or another one is set.
+pub fn set_foreground(id: usize) {
+    TTY.lock().foreground = Some(id);
+}